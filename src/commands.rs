@@ -1,62 +1,178 @@
 use crate::regex;
-use crate::regex::parser::{Input, Progress, Syntax};
+use crate::regex::Matcher;
+use crate::regex::parser::{Input, Progress};
+use crate::regex::replacement::Replacement;
+use regex_syntax::ast::Ast;
+#[cfg(test)]
 use crate::regex::equivalent::Equivalent;
 
-use ::regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use lazy_static::lazy_static;
 
-use nom;
 use nom::{Finish};
 use nom::branch::alt;
-use nom::character::complete::{anychar, char, none_of};
-use nom::combinator::{fail, opt, rest};
+use nom::bytes::complete::take_till;
+use nom::character::complete::{anychar, char, none_of, one_of, space0};
+use nom::combinator::{fail, opt, peek};
+use nom::error::{Error, ErrorKind};
 use nom::multi::many0;
 
 #[derive(Clone, Debug)]
 pub enum Address {
-    LineNumber(u64),
-    // LastLine, // TODO how do we detect last line?  From stdin, in particular
-    Context(Regex), // TODO case-insensitive
+    LineNumber(u64), // 0 only as the start of 0,/re/
+    LastLine,
+    Context(Matcher),
+    LastRegex, // the empty regex //
+    Step(u64, u64), // first~step
+    // only as the end of a range
+    Relative(u64), // addr,+N
+    Multiple(u64), // addr,~N
 }
 
+#[cfg(test)]
 impl Equivalent for Address {
     fn equivalent(&self, other: &Address) -> bool {
         use Address::*;
         match (self, other) {
             (LineNumber(n), LineNumber(m)) => n == m,
+            (LastLine, LastLine) | (LastRegex, LastRegex) => true,
             (Context(_), Context(_)) => true,
+            (Step(a, b), Step(c, d)) => a == c && b == d,
+            (Relative(n), Relative(m)) | (Multiple(n), Multiple(m)) => n == m,
             _ => false
         }
     }
 }
 
+// destination of a branch command
+// target is filled in by resolve_targets; commands.len() means the end of the script
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Jump {
+    pub label: Option<String>,
+    pub target: usize,
+}
+
+impl Jump {
+    fn new(label: Option<String>) -> Jump {
+        Jump { label, target: 0 }
+    }
+}
+
+// flags following s/regex/replacement/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubstituteFlags {
+    pub global: bool, // g
+    pub occurrence: usize, // replace only the nth match, or the nth and all later with g
+    pub print: bool, // p
+    pub write: Option<String>, // w file
+    pub case_insensitive: bool, // i or I
+    pub multi_line: bool, // m or M
+    pub execute: bool, // e
+}
+
+impl Default for SubstituteFlags {
+    fn default() -> SubstituteFlags {
+        SubstituteFlags {
+            global: false,
+            occurrence: 1,
+            print: false,
+            write: None,
+            case_insensitive: false,
+            multi_line: false,
+            execute: false,
+        }
+    }
+}
+
+// character map for y/source/dest/
+// ASCII characters are looked up in a table, others in a HashMap
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Transliteration {
+    ascii: Box<[char; 128]>,
+    other: HashMap<char, char>,
+}
+
+impl Transliteration {
+    // caller checks that source and dest are the same length
+    pub fn new(source: &[char], dest: &[char]) -> Transliteration {
+        let mut ascii = Box::new(['\0'; 128]);
+        for (i, c) in ascii.iter_mut().enumerate() {
+            *c = char::from(i as u8);
+        }
+        let mut other = HashMap::new();
+        // as in GNU sed, the last mapping for a repeated source character wins
+        for (from, to) in std::iter::zip(source, dest) {
+            if from.is_ascii() {
+                ascii[*from as usize] = *to;
+            } else {
+                other.insert(*from, *to);
+            }
+        }
+        Transliteration { ascii, other }
+    }
+
+    pub fn map(&self, c: char) -> char {
+        if c.is_ascii() {
+            self.ascii[c as usize]
+        } else {
+            *self.other.get(&c).unwrap_or(&c)
+        }
+    }
+
+    pub fn apply(&self, source: &str, destination: &mut String) {
+        destination.extend(source.chars().map(|c| self.map(c)));
+    }
+}
+
 // single letter for uppercase function names
 // F followed by a letter for lowercase function names
 #[derive(Clone, Debug)]
 pub enum Function {
     Equals,
+    Label(String),
+    Block(usize), // index of the command after the matching EndBlock, filled in by resolve_targets
+    EndBlock,
+    Fb(Jump),
     D, Fd,
     G, Fg,
     H, Fh,
+    Fa(String),
+    Fc(String),
     Fi(String),
-    Fp,
-    Fs(Regex, String),
-    Fx
+    N, Fn,
+    P, Fp,
+    Q(i32), Fq(i32), // exit status
+    R(String), Fr(String),
+    Fs(Option<Matcher>, Replacement, SubstituteFlags), // None for the empty regex
+    T(Jump), Ft(Jump),
+    W(String), Fw(String),
+    Fx,
+    Fy(Transliteration),
 }
 
+#[cfg(test)]
 impl Equivalent for Function {
     fn equivalent(&self, other: &Function) -> bool {
         use Function::*;
         match (self, other) {
             (Equals, Equals) => true,
+            (Label(a), Label(b)) => a == b,
+            (Block(_), Block(_)) | (EndBlock, EndBlock) => true,
+            (Fb(a), Fb(b)) => a.label == b.label,
             (D, D) | (Fd, Fd) => true,
             (G, G) | (Fg, Fg) => true,
             (H, H) | (Fh, Fh) => true,
-            (Fi(s), Fi(t)) => s == t,
-            (Fp, Fp) => true,
-            (Fs(_, s), Fs(_, t)) => s == t,
+            (Fa(s), Fa(t)) | (Fc(s), Fc(t)) | (Fi(s), Fi(t)) => s == t,
+            (N, N) | (Fn, Fn) => true,
+            (P, P) | (Fp, Fp) => true,
+            (Q(a), Q(b)) | (Fq(a), Fq(b)) => a == b,
+            (R(a), R(b)) | (Fr(a), Fr(b)) => a == b,
+            (W(a), W(b)) | (Fw(a), Fw(b)) => a == b,
+            (Fs(r, s, f), Fs(q, t, g)) => r.is_some() == q.is_some() && s == t && f == g,
+            (T(a), T(b)) | (Ft(a), Ft(b)) => a.label == b.label,
             (Fx, Fx) => true,
+            (Fy(a), Fy(b)) => a == b,
             _ => false
         }
     }
@@ -66,36 +182,144 @@ impl Equivalent for Function {
 pub struct Command {
     pub start: Option<Address>,
     pub end: Option<Address>, // should not be Some if start is None
+    pub negated: bool, // apply function to lines *not* matching the address
     pub function: Function,
 }
 
-fn take_until(sep: char, s: Input) -> Progress<String> {
-    let string: String = sep.to_string();
-    let str: &str = string.as_ref();
-    let (s, vec) = many0(none_of(str))(s)?;
-    Ok((s, vec.into_iter().collect()))
+// file name for r, R, w, W, and the w flag of s, which runs to the end of the line
+fn filename(s: Input) -> Progress<String> {
+    let start = s;
+    let (s, _) = space0(s)?;
+    let (s, path) = take_till(|c| c == '\n')(s)?;
+    if path.fragment().is_empty() {
+        return fail(start)
+    }
+    Ok((s, path.to_string()))
 }
 
-lazy_static! {
-    static ref DOLLAR: Regex = Regex::new(r"\$").unwrap();
-    static ref BACKSLASH_DIGITS: Regex = Regex::new(r"\\([0-9]+)").unwrap();
+// any order, each at most once; w must be last since the file name runs to the end of the line
+fn substitute_flags(mut s: Input) -> Progress<SubstituteFlags> {
+    let mut flags = SubstituteFlags::default();
+    let mut occurrence = None;
+    loop {
+        let start = s;
+        let Ok((rest, c)) = anychar::<Input, nom::error::Error<Input>>(s) else {
+            break
+        };
+        s = rest;
+        match c {
+            'g' if !flags.global => flags.global = true,
+            'p' if !flags.print => flags.print = true,
+            'i' | 'I' => flags.case_insensitive = true,
+            'm' | 'M' => flags.multi_line = true,
+            'e' => flags.execute = true,
+            '0'..='9' if occurrence.is_none() => {
+                let (rest, n) = nom::character::complete::u64(start)?;
+                if n == 0 {
+                    return fail(start)
+                }
+                occurrence = Some(n);
+                s = rest;
+            },
+            'w' => {
+                let (rest, path) = filename(s)?;
+                flags.write = Some(path);
+                s = rest;
+                break
+            },
+            'g' | 'p' | '0'..='9' => return fail(start),
+            _ => {
+                s = start;
+                break
+            }
+        }
+    }
+    if let Some(n) = occurrence {
+        flags.occurrence = n as usize;
+    }
+    Ok((s, flags))
 }
 
-// convert sed \1 syntax to regex crate $1 and escape $
-pub fn clean_replacement(syntax: &Syntax, mut s: String) -> String {
-    if syntax == &Syntax::Teal {
-        return s
+// text for a, c, and i
+// POSIX puts the text on the lines following a\, each but the last ending in \
+// GNU also accepts the text on the same line, as in `a text` or `a\text`
+// a backslash is removed from any other character it precedes
+fn text_argument(s: Input) -> Progress<String> {
+    let start = s;
+    let (s, _) = space0(s)?;
+    let (s, backslash) = opt(char('\\'))(s)?;
+    let (mut s, _) = match backslash {
+        Some(_) => opt(char('\n'))(s).map(|(s, _)| (s, ()))?,
+        None => (s, ()),
+    };
+    let mut text = String::new();
+    loop {
+        match anychar::<Input, nom::error::Error<Input>>(s) {
+            Ok((_, '\n')) | Err(_) => break,
+            Ok((rest, '\\')) => {
+                let (rest, c) = anychar(rest)?;
+                text.push(c);
+                s = rest;
+            },
+            Ok((rest, c)) => {
+                text.push(c);
+                s = rest;
+            },
+        }
+    }
+    if text.is_empty() && backslash.is_none() {
+        return fail(start)
     }
+    Ok((s, text))
+}
 
-    let mut dest = String::new();
+// characters up to the unescaped sep, for y
+// \n is newline, \\ is backslash, \ before sep is sep; other backslashes are literal
+fn transliteration_text(sep: char, mut s: Input) -> Progress<Vec<char>> {
+    let mut chars = Vec::new();
+    loop {
+        let (rest, c) = anychar(s)?;
+        if c == sep {
+            return Ok((s, chars))
+        }
+        s = rest;
+        if c != '\\' {
+            chars.push(c);
+            continue
+        }
+        let (rest, e) = anychar(s)?;
+        s = rest;
+        match e {
+            'n' => chars.push('\n'),
+            '\\' => chars.push('\\'),
+            _ if e == sep => chars.push(sep),
+            _ => {
+                chars.push('\\');
+                chars.push(e);
+            }
+        }
+    }
+}
 
-    let changed = regex::replace_all(&*DOLLAR, &s, &mut dest, "$$$$");
-    if changed {std::mem::swap(&mut s, &mut dest)}
+// optional exit status for q and Q
+fn exit_status(s: Input) -> Progress<i32> {
+    let (s, _) = space0(s)?;
+    let (s, n) = opt(nom::character::complete::i32)(s)?;
+    Ok((s, n.unwrap_or(0)))
+}
 
-    let changed = regex::replace_all(&*BACKSLASH_DIGITS, &s, &mut dest, r"$${$1}");
-    if changed {std::mem::swap(&mut s, &mut dest)}
+// labels end at newline or semicolon, as in GNU sed
+fn label(s: Input) -> Progress<String> {
+    let (s, _) = space0(s)?;
+    let (s, vec) = many0(none_of("; \t\n"))(s)?;
+    let (s, _) = space0(s)?;
+    Ok((s, vec.into_iter().collect()))
+}
 
-    s
+// branch with no label jumps to the end of the script
+fn jump(s: Input) -> Progress<Jump> {
+    let (s, l) = label(s)?;
+    Ok((s, Jump::new(if l.is_empty() { None } else { Some(l) })))
 }
 
 pub fn parse_function(cmd: Input) -> Progress<Function> {
@@ -103,39 +327,203 @@ pub fn parse_function(cmd: Input) -> Progress<Function> {
     use Function::{*};
     match function {
         '=' => Ok((s, Equals)), // spec says only allows one addr, not a 2-addr range 🤷
+        ':' => {
+            let (s, l) = label(s)?;
+            if l.is_empty() {
+                return fail(cmd)
+            }
+            Ok((s, Label(l)))
+        },
+        '{' => Ok((s, Block(0))),
+        '}' => Ok((s, EndBlock)),
+        'b' => jump(s).map(|(s, j)| (s, Fb(j))),
         'd' => Ok((s, Fd)),
         'D' => Ok((s, D)),
         'g' => Ok((s, Fg)),
         'G' => Ok((s, G)),
         'h' => Ok((s, Fh)),
         'H' => Ok((s, H)),
-        'i' => rest(s).map(|(s, i)| (s, Fi(i.to_string()))),
+        'a' => text_argument(s).map(|(s, t)| (s, Fa(t))),
+        'c' => text_argument(s).map(|(s, t)| (s, Fc(t))),
+        'i' => text_argument(s).map(|(s, t)| (s, Fi(t))),
+        'n' => Ok((s, Fn)),
+        'N' => Ok((s, N)),
+        'P' => Ok((s, P)),
+        'r' => filename(s).map(|(s, path)| (s, Fr(path))),
+        'R' => filename(s).map(|(s, path)| (s, R(path))),
+        'w' => filename(s).map(|(s, path)| (s, Fw(path))),
+        'W' => filename(s).map(|(s, path)| (s, W(path))),
+        'q' => exit_status(s).map(|(s, n)| (s, Fq(n))),
+        'Q' => exit_status(s).map(|(s, n)| (s, Q(n))),
         'p' => Ok((s, Fp)),
         's' => {
+            let (pattern, sep) = anychar(s)?;
+            let (s, ast) = opt(|s| regex::parser::parse(sep, s))(pattern)?;
+            let groups = s.extra.last_regex as usize;
+            let (template, _) = char(sep)(s)?;
+            let (s, replacement) = regex::replacement::parse(sep, template)?;
+            // GNU: a reference to a group the regex doesn't have is an error
+            if ast.is_some() && replacement.max_group() > groups {
+                return fail(template)
+            }
+            let (s, _) = char(sep)(s)?;
+            let (rest, flags) = substitute_flags(s)?;
+            let regex = match ast {
+                Some(ast) => Some(compile(pattern, &ast, flags.case_insensitive, flags.multi_line)?),
+                // the last regex is reused as it was compiled
+                None if flags.case_insensitive || flags.multi_line => return fail(s),
+                None => None,
+            };
+            Ok((rest, Fs(regex, replacement, flags)))
+        },
+        't' => jump(s).map(|(s, j)| (s, Ft(j))),
+        'T' => jump(s).map(|(s, j)| (s, T(j))),
+        'x' => Ok((s, Fx)),
+        'y' => {
             let (s, sep) = anychar(s)?;
-            let (s, ast) = regex::parser::parse(sep, s)?;
+            let (s, source) = transliteration_text(sep, s)?;
             let (s, _) = char(sep)(s)?;
-            let regex = Regex::new(&format!("{}", ast)).unwrap();
-            let (s, replacement) = take_until(sep, s)?;
+            let (s, dest) = transliteration_text(sep, s)?;
             let (s, _) = char(sep)(s)?;
-            Ok((s, Fs(regex, clean_replacement(&s.extra.syntax, replacement))))
+            if source.len() != dest.len() {
+                return fail(cmd)
+            }
+            Ok((s, Fy(Transliteration::new(&source, &dest))))
         },
-        'x' => Ok((s, Fx)),
         _ => fail(cmd)
     }
 }
 
+// the regex most recently used by an address or s command, which the empty regex refers to
+pub fn last_regex(last: Option<&Matcher>) -> io::Result<&Matcher> {
+    last.ok_or_else(|| invalid("no previous regular expression".to_string()))
+}
+
 // handles only a single address
 // caller must maintain state between calls, decide whether to pass start or end pattern
-pub fn match_address(addr: &Address, text: &str, line_num: u64) -> bool {
-    match addr {
+// last_line is true when no input follows the current line
+// last is updated when a regex is tried, whether or not it matches
+pub fn match_address<'a>(addr: &'a Address, text: &str, line_num: u64, last_line: bool, last: &mut Option<&'a Matcher>) -> io::Result<bool> {
+    Ok(match addr {
         Address::LineNumber(l) => *l == line_num,
-        Address::Context(regex) => regex.is_match(text),
+        Address::LastLine => last_line,
+        Address::Context(regex) => {
+            *last = Some(regex);
+            regex.is_match(text)
+        },
+        Address::LastRegex => last_regex(*last)?.is_match(text),
+        // GNU: a step of 0 matches only the first line
+        Address::Step(first, step) => if *step == 0 {
+            line_num == *first
+        } else {
+            line_num >= *first && (line_num - first).is_multiple_of(*step)
+        },
+        Address::Relative(_) | Address::Multiple(_) => panic!("{:?} is only valid as the end of a range", addr),
+    })
+}
+
+// state of a two-address command, from one line to the next
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RangeState {
+    Closed,
+    Open(u64), // line number where the range started
+}
+
+impl RangeState {
+    // 0,/re/ starts out open, so its end may match the first line
+    pub fn new(start: &Address) -> RangeState {
+        match start {
+            Address::LineNumber(0) => RangeState::Open(0),
+            _ => RangeState::Closed,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self, RangeState::Open(_))
+    }
+
+    // true if the current line is within the range start,end
+    pub fn matches<'a>(&mut self, start: &'a Address, end: &'a Address, text: &str, line_num: u64, last_line: bool,
+                       last: &mut Option<&'a Matcher>) -> io::Result<bool> {
+        use Address::*;
+        Ok(match *self {
+            RangeState::Closed => {
+                if !match_address(start, text, line_num, last_line, last)? {
+                    return Ok(false)
+                }
+                // a numeric end at or before the start line matches only one line
+                // other ends are checked starting with the next line
+                let one_line = match end {
+                    LineNumber(n) => *n <= line_num,
+                    Relative(n) => *n == 0,
+                    Multiple(n) => *n == 0 || line_num.is_multiple_of(*n),
+                    _ => false,
+                };
+                if !one_line {
+                    *self = RangeState::Open(line_num);
+                }
+                true
+            },
+            RangeState::Open(first) => {
+                // n or N may read past a numeric end; then the range closes without matching
+                let last_in_range = match end {
+                    LineNumber(n) => Some(*n),
+                    Relative(n) => Some(first + n),
+                    _ => None,
+                };
+                match last_in_range {
+                    Some(n) => {
+                        if line_num >= n {
+                            *self = RangeState::Closed;
+                        }
+                        line_num <= n
+                    },
+                    None => {
+                        let stop = match end {
+                            Multiple(n) => *n == 0 || line_num.is_multiple_of(*n),
+                            _ => match_address(end, text, line_num, last_line, last)?,
+                        };
+                        if stop {
+                            *self = RangeState::Closed;
+                        }
+                        true
+                    },
+                }
+            },
+        })
     }
 }
 
 pub fn parse_address(s: Input) -> Progress<Address> {
-    alt((line_number_addr, context_addr))(s)
+    alt((step_addr, line_number_addr, last_line_addr, context_addr))(s)
+}
+
+fn end_address(s: Input) -> Progress<Address> {
+    alt((relative_addr, multiple_addr, parse_address))(s)
+}
+
+fn step_addr(s: Input) -> Progress<Address> {
+    let (s, first) = nom::character::complete::u64(s)?;
+    let (s, _) = char('~')(s)?;
+    let (s, step) = nom::character::complete::u64(s)?;
+    Ok((s, Address::Step(first, step)))
+}
+
+fn relative_addr(s: Input) -> Progress<Address> {
+    let (s, _) = char('+')(s)?;
+    let (s, n) = nom::character::complete::u64(s)?;
+    Ok((s, Address::Relative(n)))
+}
+
+fn multiple_addr(s: Input) -> Progress<Address> {
+    let (s, _) = char('~')(s)?;
+    let (s, n) = nom::character::complete::u64(s)?;
+    Ok((s, Address::Multiple(n)))
+}
+
+fn last_line_addr(s: Input) -> Progress<Address> {
+    let (s, _) = char('$')(s)?;
+    Ok((s, Address::LastLine))
 }
 
 fn line_number_addr(s: Input) -> Progress<Address> {
@@ -148,16 +536,30 @@ fn backslash_char(s: Input) -> Progress<char> {
     anychar(s)
 }
 
+// GNU: /re/I is case-insensitive, and in /re/M ^ and $ match at embedded newlines
+// lowercase i would be the insert command, so only uppercase modifiers are accepted
+// the empty regex // takes no modifiers
+// a regex which parses but can't be compiled, such as a\{3,1\}, is reported as a parse error at its start
+fn compile<'a>(pattern: Input<'a>, ast: &Ast, case_insensitive: bool, multi_line: bool) -> Result<Matcher, nom::Err<Error<Input<'a>>>> {
+    Matcher::new(ast, case_insensitive, multi_line, pattern.extra.longest, pattern.extra.separator)
+        .map_err(|_| nom::Err::Failure(Error::new(pattern, ErrorKind::Verify)))
+}
+
 fn context_addr(s: Input) -> Progress<Address> {
     let (s, sep) = alt((char('/'), backslash_char))(s)?;
-    let (s, ast) = regex::parser::parse(sep, s)?;
-    let regex = Regex::new(&format!("{}", ast)).unwrap();
+    if let Ok((s, _)) = char::<Input, nom::error::Error<Input>>(sep)(s) {
+        return Ok((s, Address::LastRegex))
+    }
+    let pattern = s;
+    let (s, ast) = regex::parser::parse(sep, pattern)?;
     let (s, _) = char(sep)(s)?;
+    let (s, modifiers) = many0(one_of("IM"))(s)?;
+    let regex = compile(pattern, &ast, modifiers.contains(&'I'), modifiers.contains(&'M'))?;
     Ok((s, Address::Context(regex)))
 }
 
-pub fn parse_command(s: Input) -> Progress<Command> {
-    let (s, start) = opt(|s|parse_address(s))(s)?;
+pub fn parse_command(cmd: Input) -> Progress<Command> {
+    let (s, start) = opt(|s|parse_address(s))(cmd)?;
     let (s, end) = match &start {
         None => Ok((s, None)),
         Some(_) => {
@@ -165,33 +567,152 @@ pub fn parse_command(s: Input) -> Progress<Command> {
             match maybe {
                 None => Ok((s, None)),
                 Some(_) => {
-                    let (s, addr) = parse_address(s)?;
+                    let (s, addr) = end_address(s)?;
                     Ok((s, Some(addr)))
                 }
             }
         }
     }?;
+    // line 0 is only meaningful as 0,/re/
+    if matches!(start, Some(Address::LineNumber(0))) && !matches!(end, Some(Address::Context(_) | Address::LastRegex)) {
+        return fail(cmd)
+    }
+    let (s, negated) = opt(char('!'))(s)?;
+    let (s, _) = space0(s)?;
     let (s, function) = parse_function(s)?;
     Ok((s, Command {
-        start:  start,
-        end: end,
-        function: function
+        start,
+        end,
+        negated: negated.is_some(),
+        function
     }))
 }
 
-pub fn parse_command_finish(s: Input) -> io::Result<Command> {
-    match parse_command(s).finish() {
-        Ok((_, cmd)) => Ok(cmd),
-        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)))
+fn comment(s: Input) -> Progress<()> {
+    let (s, _) = char('#')(s)?;
+    let (s, _) = take_till(|c| c == '\n')(s)?;
+    Ok((s, ()))
+}
+
+// whitespace, semicolons, and comments between commands
+fn separators(s: Input) -> Progress<()> {
+    let (s, _) = many0(alt((comment, |s| one_of(" \t\n;")(s).map(|(s, _)| (s, ())))))(s)?;
+    Ok((s, ()))
+}
+
+// a command ends at a separator, a comment, a closing brace, or the end of the script
+fn command_end(s: Input) -> Progress<()> {
+    let (s, _) = space0(s)?;
+    if s.fragment().is_empty() {
+        return Ok((s, ()))
+    }
+    let (s, _) = peek(one_of(";\n#}"))(s)?;
+    Ok((s, ()))
+}
+
+pub fn parse_script(s: Input) -> Progress<Vec<Command>> {
+    let mut commands = Vec::new();
+    let (mut s, _) = separators(s)?;
+    while !s.fragment().is_empty() {
+        let (rest, cmd) = parse_command(s)?;
+        let (rest, _) = match cmd.function {
+            Function::Block(_) => Ok((rest, ())),
+            _ => command_end(rest)
+        }?;
+        commands.push(cmd);
+        let (rest, _) = separators(rest)?;
+        s = rest;
+    }
+    Ok((s, commands))
+}
+
+// where a script failed to parse
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: u32,
+    pub column: usize,
+    pub context: String, // the rest of the line
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: cannot parse \"{}\"", self.line, self.column, self.context)
+    }
+}
+
+impl From<ScriptError> for io::Error {
+    fn from(e: ScriptError) -> io::Error {
+        invalid(e.to_string())
     }
 }
 
+pub fn parse_script_finish(s: Input) -> Result<Vec<Command>, ScriptError> {
+    match parse_script(s).finish() {
+        Ok((_, commands)) => Ok(commands),
+        Err(e) => Err(ScriptError {
+            line: e.input.location_line(),
+            column: e.input.get_column(),
+            context: e.input.fragment().lines().next().unwrap_or("").to_string(),
+        }),
+    }
+}
+
+// POSIX: if the first two characters of the script are #n, behave as if -n were given
+pub fn suppresses_output(script: &str) -> bool {
+    script == "#n" || script.starts_with("#n\n")
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// point each branch at the command following its label
+// and each block at the command following its closing brace
+pub fn resolve_targets(commands: &mut [Command]) -> io::Result<()> {
+    let mut labels = HashMap::new();
+    let mut open_blocks = Vec::new();
+    for ix in 0..commands.len() {
+        match &commands[ix].function {
+            Function::Label(l) if labels.insert(l.clone(), ix + 1).is_some() =>
+                return Err(invalid(format!("duplicate label \"{}\"", l))),
+            Function::Block(_) => open_blocks.push(ix),
+            Function::EndBlock => {
+                if commands[ix].start.is_some() {
+                    return Err(invalid("} doesn't want any addresses".to_string()))
+                }
+                match open_blocks.pop() {
+                    Some(open) => commands[open].function = Function::Block(ix + 1),
+                    None => return Err(invalid("unexpected `}'".to_string()))
+                }
+            },
+            _ => ()
+        }
+    }
+    if !open_blocks.is_empty() {
+        return Err(invalid("unmatched `{'".to_string()))
+    }
+    let end = commands.len();
+    for cmd in commands.iter_mut() {
+        use Function::{*};
+        if let Fb(jump) | Ft(jump) | T(jump) = &mut cmd.function {
+            jump.target = match &jump.label {
+                None => end,
+                Some(l) => match labels.get(l) {
+                    Some(ix) => *ix,
+                    None => return Err(invalid(format!("can't find label for jump to \"{}\"", l)))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use super::Address::*;
     use super::Function::*;
-    use crate::new_regex_input;
+    use crate::regex::parser::{Syntax, new_regex_input};
     use assert_ok::assert_ok;
 
     fn function_equivalent(input: &str, expected: &Function, complete: bool) {
@@ -203,8 +724,12 @@ pub mod tests {
         }
     }
 
-    fn dummy_regex() -> Regex {
-        Regex::new(".").unwrap() // ignored in equivalence
+    fn literal_replacement(text: &str) -> Replacement {
+        Replacement { pieces: vec![regex::replacement::Piece::Literal(text.to_string())] }
+    }
+
+    fn dummy_regex() -> Matcher {
+        Matcher::Fast(::regex::Regex::new(".").unwrap()) // ignored in equivalence
     }
 
     #[test]
@@ -212,14 +737,181 @@ pub mod tests {
         function_equivalent("d", &Fd, true);
     }
 
+    #[test]
+    fn fun_a_one_liner() {
+        function_equivalent("a  hello; world", &Fa("hello; world".to_string()), true);
+    }
+
+    #[test]
+    fn fun_i_backslash_one_liner() {
+        function_equivalent("i\\  indented", &Fi("  indented".to_string()), true);
+    }
+
+    #[test]
+    fn fun_c_multi_line() {
+        function_equivalent("c\\\nfirst\\\nsecond\nnext", &Fc("first\nsecond".to_string()), false);
+    }
+
+    #[test]
+    fn fun_a_missing_text() {
+        assert!(parse_function(new_regex_input("a")).is_err());
+    }
+
+    #[test]
+    fn fun_q() {
+        function_equivalent("q", &Fq(0), true);
+        function_equivalent("Q 3", &Q(3), true);
+    }
+
+    #[test]
+    fn fun_y() {
+        let expected = Transliteration::new(&['a', '\n', '/', '\\'], &['é', 'b', 'c', 'd']);
+        function_equivalent(r"y/a\n\/\\/ébcd/", &Fy(expected), true);
+    }
+
+    #[test]
+    fn fun_y_unequal_lengths() {
+        assert!(parse_function(new_regex_input("y/abc/xy/")).is_err());
+        // counts characters, not bytes
+        assert!(parse_function(new_regex_input("y/ab/é/")).is_err());
+    }
+
+    #[test]
+    fn transliterate() {
+        let y = Transliteration::new(&['a', 'é'], &['é', 'a']);
+        let mut dest = String::new();
+        y.apply("bébé à la", &mut dest);
+        assert_eq!(dest, "baba à lé");
+    }
+
+    #[test]
+    fn fun_files() {
+        function_equivalent("r in.txt", &Fr("in.txt".to_string()), true);
+        function_equivalent("R  in.txt", &R("in.txt".to_string()), true);
+        function_equivalent("w out; txt", &Fw("out; txt".to_string()), true);
+        function_equivalent("W/dev/stdout", &W("/dev/stdout".to_string()), true);
+        assert!(parse_function(new_regex_input("w")).is_err());
+    }
+
+    #[test]
+    fn fun_label() {
+        function_equivalent(":a", &Label("a".to_string()), true);
+    }
+
+    #[test]
+    fn fun_b_no_label() {
+        function_equivalent("b", &Fb(Jump::new(None)), true);
+    }
+
+    #[test]
+    fn fun_t_label() {
+        function_equivalent("t loop", &Ft(Jump::new(Some("loop".to_string()))), true);
+    }
+
+    #[test]
+    fn resolve_missing_label() {
+        let mut commands = vec![Command { start: None, end: None, negated: false, function: Fb(Jump::new(Some("x".to_string()))) }];
+        assert!(resolve_targets(&mut commands).is_err());
+    }
+
+    fn parse_commands(inputs: &[&str]) -> Vec<Command> {
+        inputs.iter().flat_map(|i| assert_ok!(parse_script_finish(new_regex_input(i)))).collect()
+    }
+
+    #[test]
+    fn script_separators() {
+        let commands = parse_commands(&["p; p\n\n  p;"]);
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn script_comments() {
+        let commands = parse_commands(&["# leading\np # trailing\n#n\nd"]);
+        assert_eq!(commands.len(), 2);
+        assert!(commands[1].function.equivalent(&Fd));
+    }
+
+    #[test]
+    fn script_braces() {
+        let commands = parse_commands(&["/a/{p;s/a/b/}"]);
+        assert_eq!(commands.len(), 4);
+        assert!(commands[3].function.equivalent(&EndBlock));
+    }
+
+    #[test]
+    fn script_trailing_garbage() {
+        let r = parse_script_finish(new_regex_input("p\ns/a/b/x"));
+        let e = r.expect_err("trailing input should be rejected");
+        assert!(e.to_string().starts_with("line 2, column 7"), "{}", e);
+    }
+
+    #[test]
+    fn quiet_script() {
+        assert!(suppresses_output("#n"));
+        assert!(suppresses_output("#n\np"));
+        assert!(!suppresses_output("#no\np"));
+        assert!(!suppresses_output(" #n"));
+    }
+
+    #[test]
+    fn resolve_block() {
+        let mut commands = parse_commands(&["/a/{", "p", "}", "p"]);
+        assert_ok!(resolve_targets(&mut commands));
+        assert!(matches!(commands[0].function, Block(3)));
+    }
+
+    #[test]
+    fn resolve_unmatched_block() {
+        let mut commands = parse_commands(&["{", "{", "}"]);
+        assert!(resolve_targets(&mut commands).is_err());
+        let mut commands = parse_commands(&["}"]);
+        assert!(resolve_targets(&mut commands).is_err());
+    }
+
+    #[test]
+    fn negated_address() {
+        let (_, cmd) = assert_ok!(parse_command(new_regex_input("/^#/!d")));
+        assert!(cmd.negated);
+        assert!(cmd.function.equivalent(&Fd));
+    }
+
     #[test]
     fn s_slash() {
-        function_equivalent("s/a/b/", &Fs(dummy_regex(), String::from("b")), true);
+        function_equivalent("s/a/b/", &Fs(Some(dummy_regex()), literal_replacement("b"), SubstituteFlags::default()), true);
     }
 
     #[test]
     fn s_comma() {
-        function_equivalent("s,a,b,", &Fs(dummy_regex(), String::from("b")), true);
+        function_equivalent("s,a,b,", &Fs(Some(dummy_regex()), literal_replacement("b"), SubstituteFlags::default()), true);
+    }
+
+    #[test]
+    fn s_flags() {
+        let flags = SubstituteFlags {
+            global: true,
+            occurrence: 3,
+            print: true,
+            case_insensitive: true,
+            ..SubstituteFlags::default()
+        };
+        function_equivalent("s/a/b/3gpI", &Fs(Some(dummy_regex()), literal_replacement("b"), flags), true);
+    }
+
+    #[test]
+    fn s_write_flag() {
+        let flags = SubstituteFlags {
+            write: Some("out.txt".to_string()),
+            multi_line: true,
+            ..SubstituteFlags::default()
+        };
+        function_equivalent("s/a/b/Mw out.txt", &Fs(Some(dummy_regex()), literal_replacement("b"), flags), true);
+    }
+
+    #[test]
+    fn s_bad_flags() {
+        assert!(parse_function(new_regex_input("s/a/b/0")).is_err());
+        assert!(parse_function(new_regex_input("s/a/b/gg")).is_err());
+        assert!(parse_function(new_regex_input("s/a/b/w")).is_err());
     }
 
     fn address_equivalent(input: &str, expected: &Address) {
@@ -229,28 +921,195 @@ pub mod tests {
         assert_eq!(rest.fragment(), &"");
     }
 
+    #[test]
+    fn addr_last() {
+        address_equivalent("$", &LastLine)
+    }
+
+    #[test]
+    fn addr_step() {
+        address_equivalent("0~4", &Step(0, 4))
+    }
+
+    #[test]
+    fn addr_relative_ends() {
+        let (_, cmd) = assert_ok!(parse_command(new_regex_input("/a/,+2p")));
+        assert!(cmd.end.unwrap().equivalent(&Relative(2)));
+        let (_, cmd) = assert_ok!(parse_command(new_regex_input("5,~4p")));
+        assert!(cmd.end.unwrap().equivalent(&Multiple(4)));
+    }
+
+    #[test]
+    fn addr_zero() {
+        assert!(parse_command(new_regex_input("0,/a/p")).is_ok());
+        assert!(parse_command(new_regex_input("0,5p")).is_err());
+        assert!(parse_command(new_regex_input("0p")).is_err());
+    }
+
+    #[test]
+    fn match_step() {
+        let step = Step(1, 3);
+        let lines: Vec<u64> = (1..=10).filter(|n| assert_ok!(match_address(&step, "", *n, false, &mut None))).collect();
+        assert_eq!(lines, [1, 4, 7, 10]);
+        assert!(assert_ok!(match_address(&Step(2, 0), "", 2, false, &mut None)));
+        assert!(!assert_ok!(match_address(&Step(2, 0), "", 4, false, &mut None)));
+    }
+
+    fn range_lines(range: &str, lines: &[&str]) -> Vec<u64> {
+        let (_, cmd) = assert_ok!(parse_command(new_regex_input(&format!("{}p", range))));
+        let (start, end) = (cmd.start.unwrap(), cmd.end.unwrap());
+        let mut state = RangeState::new(&start);
+        let mut matched = Vec::new();
+        for (i, text) in lines.iter().enumerate() {
+            let line_num = i as u64 + 1;
+            if assert_ok!(state.matches(&start, &end, text, line_num, line_num == lines.len() as u64, &mut None)) {
+                matched.push(line_num);
+            }
+        }
+        matched
+    }
+
+    #[test]
+    fn range_regex_regex() {
+        assert_eq!(range_lines("/b/,/d/", &["a", "b", "c", "d", "e"]), [2, 3, 4]);
+    }
+
+    #[test]
+    fn range_end_not_checked_on_start_line() {
+        assert_eq!(range_lines("/x/,/x/", &["x", "a", "x", "b"]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn range_number_regex() {
+        assert_eq!(range_lines("2,/x/", &["x", "a", "b", "x", "c"]), [2, 3, 4]);
+    }
+
+    #[test]
+    fn range_regex_number() {
+        assert_eq!(range_lines("/a/,4", &["x", "a", "b", "c", "a", "d"]), [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn range_backwards_numbers() {
+        assert_eq!(range_lines("3,1", &["a", "b", "c", "d"]), [3]);
+        assert_eq!(range_lines("2,2", &["a", "b", "c", "d"]), [2]);
+    }
+
+    #[test]
+    fn range_regex_backwards_number() {
+        assert_eq!(range_lines("/b/,1", &["a", "b", "c", "b"]), [2, 4]);
+    }
+
+    #[test]
+    fn range_reopens() {
+        assert_eq!(range_lines("/s/,/e/", &["s", "a", "e", "b", "s", "e", "c"]), [1, 2, 3, 5, 6]);
+    }
+
+    #[test]
+    fn range_unterminated() {
+        assert_eq!(range_lines("/b/,/z/", &["a", "b", "c"]), [2, 3]);
+    }
+
+    #[test]
+    fn range_zero_regex() {
+        assert_eq!(range_lines("0,/a/", &["a", "a", "b"]), [1]);
+        assert_eq!(range_lines("1,/a/", &["a", "a", "b"]), [1, 2]);
+    }
+
+    #[test]
+    fn range_relative() {
+        assert_eq!(range_lines("/a/,+1", &["a", "b", "c", "a", "a", "d"]), [1, 2, 4, 5]);
+        assert_eq!(range_lines("/a/,+0", &["a", "b", "a"]), [1, 3]);
+    }
+
+    #[test]
+    fn range_multiple() {
+        assert_eq!(range_lines("5,~4", &["1", "2", "3", "4", "5", "6", "7", "8", "9"]), [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn range_skipped_end() {
+        let start = LineNumber(1);
+        let end = LineNumber(2);
+        let mut state = RangeState::new(&start);
+        assert!(assert_ok!(state.matches(&start, &end, "", 1, false, &mut None)));
+        // line 2 was read by N, within the cycle of line 1
+        assert!(!assert_ok!(state.matches(&start, &end, "", 3, false, &mut None)));
+        assert!(!state.is_open());
+    }
+
     #[test]
     fn addr_slash() {
         address_equivalent("/foo/", &Context(dummy_regex()))
     }
 
+    #[test]
+    fn addr_modifiers() {
+        let (rest, addr) = assert_ok!(parse_address(new_regex_input("/error/IMd")));
+        assert_eq!(rest.fragment(), &"d");
+        assert!(assert_ok!(match_address(&addr, "first\nAn ERROR", 1, false, &mut None)));
+        let (rest, addr) = assert_ok!(parse_address(new_regex_input("/^b/M")));
+        assert_eq!(rest.fragment(), &"");
+        assert!(assert_ok!(match_address(&addr, "a\nb", 1, false, &mut None)));
+    }
+
+    #[test]
+    fn addr_case_sensitive_by_default() {
+        let (rest, addr) = assert_ok!(parse_address(new_regex_input("/error/i\\")));
+        assert_eq!(rest.fragment(), &"i\\");
+        assert!(!assert_ok!(match_address(&addr, "ERROR", 1, false, &mut None)));
+    }
+
     #[test]
     fn addr_comma() {
         address_equivalent("\\,foo,", &Context(dummy_regex()))
     }
 
     #[test]
-    fn clean_noop() {
-        assert_eq!(clean_replacement(&Syntax::Extended, "foo".to_string()), "foo")
+    fn addr_empty_regex() {
+        address_equivalent("//", &LastRegex);
+        assert!(parse_command(new_regex_input("0,//p")).is_ok());
+        assert!(parse_command(new_regex_input("//Ip")).is_err());
+    }
+
+    #[test]
+    fn match_empty_regex() {
+        let regex = dummy_regex();
+        assert!(assert_ok!(match_address(&LastRegex, "foo", 1, false, &mut Some(&regex))));
+        assert!(match_address(&LastRegex, "foo", 1, false, &mut None).is_err());
     }
 
     #[test]
-    fn clean_ref() {
-        assert_eq!(clean_replacement(&Syntax::Extended, r"foo\1".to_string()), "foo${1}")
+    fn substitute_empty_regex() {
+        function_equivalent("s//b/", &Fs(None, literal_replacement("b"), SubstituteFlags::default()), true);
+        assert!(parse_command(new_regex_input("s//b/I")).is_err());
+    }
+
+    #[test]
+    fn backreference_to_group_of_earlier_regex() {
+        let mut s = new_regex_input(r"s/\(a\)/x/;s/b\1/y/");
+        s.extra.syntax = Syntax::Basic;
+        assert!(parse_script_finish(s).is_err());
+    }
+
+    #[test]
+    fn invalid_repetition_range() {
+        for script in [r"s/a\{3,1\}/x/", r"/a\{3,1\}/p"] {
+            for longest in [false, true] {
+                let mut s = new_regex_input(script);
+                s.extra.syntax = Syntax::Basic;
+                s.extra.longest = longest;
+                let error = parse_script_finish(s).unwrap_err();
+                assert!(error.to_string().contains(r#"cannot parse "a\{3,1\}"#), "{}", error);
+            }
+        }
     }
 
     #[test]
-    fn clean_dollar() {
-        assert_eq!(clean_replacement(&Syntax::Extended, "$foo".to_string()), "$$foo")
+    fn substitute_missing_group() {
+        let error = parse_script_finish(new_regex_input(r"s/a/[\1]/")).unwrap_err();
+        assert!(error.to_string().contains(r#"cannot parse "[\1]/""#), "{}", error);
+        assert!(parse_script_finish(new_regex_input(r"s/(a)(?P<b>b)/\2/")).is_ok());
+        assert!(parse_script_finish(new_regex_input(r"s/(a)/x/;s//\2/")).is_ok());
     }
 }
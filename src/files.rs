@@ -0,0 +1,146 @@
+use crate::commands::{Command, Function};
+use crate::records::{RecordWriter, Records, chomp};
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+
+// file names which refer to an existing handle rather than a new file
+const STDOUT: &str = "/dev/stdout";
+const STDERR: &str = "/dev/stderr";
+
+// Files written by w, W, and the w flag of s
+// All commands naming the same file share one handle.
+#[derive(Debug, Default)]
+pub struct WriteFiles {
+    files: HashMap<String, BufWriter<File>>,
+}
+
+impl WriteFiles {
+    // POSIX: every w file is created before any input is read, even if it is never written
+    pub fn open(commands: &[Command]) -> io::Result<WriteFiles> {
+        let mut w_files = WriteFiles::default();
+        for cmd in commands {
+            use Function::*;
+            let path = match &cmd.function {
+                Fw(path) | W(path) => path,
+                Fs(_, _, flags) => match &flags.write {
+                    Some(path) => path,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            w_files.get(path)?;
+        }
+        Ok(w_files)
+    }
+
+    // files not opened in advance are created on first use
+    fn get(&mut self, path: &str) -> io::Result<Option<&mut BufWriter<File>>> {
+        if path == STDOUT || path == STDERR {
+            return Ok(None)
+        }
+        Ok(Some(match self.files.entry(path.to_string()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(BufWriter::new(File::create(path)?)),
+        }))
+    }
+
+    // /dev/stdout is the same output as the pattern space is printed to
+    // records are always followed by the output's separator
+    pub fn write_record(&mut self, path: &str, record: &str, output: &mut RecordWriter) -> io::Result<()> {
+        let separator = output.separator();
+        match self.get(path)? {
+            Some(file) => RecordWriter::new(file, separator).write_record(record, true),
+            None if path == STDOUT => output.write_record(record, true),
+            None => RecordWriter::new(&mut io::stderr(), separator).write_record(record, true),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for file in self.files.values_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Files read a line at a time by R
+// The position in each file is kept for the whole run.
+#[derive(Debug, Default)]
+pub struct ReadFiles {
+    // None once the file is exhausted, or if it couldn't be opened
+    files: HashMap<String, Option<Records<BufReader<File>>>>,
+}
+
+impl ReadFiles {
+    // as in GNU sed, a file which can't be read is treated as empty
+    pub fn read_line(&mut self, path: &str, separator: u8) -> Option<String> {
+        let records = self.files.entry(path.to_string())
+            .or_insert_with(|| File::open(path).ok().map(|f| Records::new(BufReader::new(f), separator)));
+        let line = records.as_mut()?.next().and_then(|l| l.ok());
+        match line {
+            Some(mut line) => {
+                chomp(&mut line, separator);
+                Some(line)
+            },
+            None => {
+                *records = None;
+                None
+            },
+        }
+    }
+}
+
+// text to print at the end of the cycle, from a, r, and R
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Appended {
+    Text(String), // followed by a newline
+    Record(String), // followed by the record separator
+    File(String), // contents are read when printed
+}
+
+pub fn flush_appended(queue: &mut Vec<Appended>, output: &mut RecordWriter) -> io::Result<()> {
+    for appended in queue.drain(..) {
+        match appended {
+            Appended::Text(text) => writeln!(output, "{}", text)?,
+            Appended::Record(record) => output.write_record(&record, true)?,
+            // as in GNU sed, a file which can't be read is silently ignored
+            Appended::File(path) => if let Ok(mut file) = File::open(&path) {
+                io::copy(&mut file, output)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assert_ok::assert_ok;
+    use std::fs;
+
+    #[test]
+    fn read_lines_in_turn() {
+        let path = std::env::temp_dir().join(format!("tsed-read-{}", std::process::id()));
+        assert_ok!(fs::write(&path, "1\n2\n"));
+        let path = path.display().to_string();
+        let mut r_files = ReadFiles::default();
+        assert_eq!(r_files.read_line(&path, b'\n'), Some("1".to_string()));
+        assert_eq!(r_files.read_line(&path, b'\n'), Some("2".to_string()));
+        assert_eq!(r_files.read_line(&path, b'\n'), None);
+        assert_eq!(r_files.read_line("/nonexistent/tsed", b'\n'), None);
+        assert_ok!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn write_stdout_to_output() {
+        let mut w_files = WriteFiles::default();
+        let mut output = Vec::new();
+        assert_ok!(w_files.write_record("/dev/stdout", "line", &mut RecordWriter::new(&mut output, b'\n')));
+        assert_eq!(output, b"line\n");
+        assert!(w_files.files.is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Output for in-place editing
+// Written to a temporary file in the same directory as the original,
+// which replaces the original by an atomic rename in commit,
+// so a crash never leaves a truncated file behind.
+pub struct InPlaceFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    temp: BufWriter<File>,
+}
+
+impl InPlaceFile {
+    pub fn create(path: &Path) -> io::Result<InPlaceFile> {
+        let dir = match path.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+        let name = path.file_name().ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput, format!("couldn't edit {}: not a regular file", path.display())))?;
+        loop {
+            let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let temp_path = dir.join(format!(".{}.tsed{}-{}", name.to_string_lossy(), std::process::id(), n));
+            match File::options().write(true).create_new(true).open(&temp_path) {
+                Ok(file) => return Ok(InPlaceFile {
+                    path: path.to_path_buf(),
+                    temp_path,
+                    temp: BufWriter::new(file),
+                }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // replace the original file, first saving it under the backup name if suffix is not empty
+    pub fn commit(mut self, suffix: &str) -> io::Result<()> {
+        self.temp.flush()?;
+        let metadata = fs::metadata(&self.path)?;
+        let file = self.temp.get_ref();
+        file.set_permissions(metadata.permissions())?;
+        preserve_owner(file, &metadata);
+        file.sync_all()?;
+
+        if !suffix.is_empty() {
+            let backup = backup_path(&self.path, suffix);
+            match fs::remove_file(&backup) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            // a hard link keeps the original in place until the rename below
+            if fs::hard_link(&self.path, &backup).is_err() {
+                fs::copy(&self.path, &backup)?;
+            }
+        }
+        fs::rename(&self.temp_path, &self.path)
+    }
+
+    // discard the partial output, leaving the original untouched
+    pub fn abort(self) {
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+impl Write for InPlaceFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.temp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.temp.flush()
+    }
+}
+
+// ownership can only be changed by a privileged user; otherwise the editor owns the new file, as in GNU sed
+#[cfg(unix)]
+fn preserve_owner(file: &File, metadata: &fs::Metadata) {
+    use std::os::unix::fs::{fchown, MetadataExt};
+    let _ = fchown(file, Some(metadata.uid()), Some(metadata.gid()));
+}
+
+#[cfg(not(unix))]
+fn preserve_owner(_file: &File, _metadata: &fs::Metadata) {}
+
+// GNU sed: each * in the suffix is replaced by the file name, otherwise the suffix is appended
+// a backup name without / goes in the same directory as the original
+pub fn backup_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let backup = if suffix.contains('*') {
+        suffix.replace('*', &name)
+    } else {
+        format!("{}{}", name, suffix)
+    };
+    if backup.contains('/') {
+        PathBuf::from(backup)
+    } else {
+        path.with_file_name(backup)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assert_ok::assert_ok;
+
+    #[test]
+    fn backup_append() {
+        assert_eq!(backup_path(Path::new("dir/file.txt"), ".bak"), PathBuf::from("dir/file.txt.bak"));
+    }
+
+    #[test]
+    fn backup_star() {
+        assert_eq!(backup_path(Path::new("dir/file.txt"), "old_*"), PathBuf::from("dir/old_file.txt"));
+    }
+
+    #[test]
+    fn backup_directory() {
+        assert_eq!(backup_path(Path::new("dir/file.txt"), "bak/*"), PathBuf::from("bak/file.txt"));
+    }
+
+    #[test]
+    fn commit_replaces_original() {
+        let dir = std::env::temp_dir().join(format!("tsed-in-place-{}", std::process::id()));
+        assert_ok!(fs::create_dir_all(&dir));
+        let path = dir.join("edit.txt");
+        assert_ok!(fs::write(&path, "before\n"));
+
+        let mut out = assert_ok!(InPlaceFile::create(&path));
+        assert_ok!(out.write_all(b"after\n"));
+        assert_eq!(assert_ok!(fs::read_to_string(&path)), "before\n");
+        assert_ok!(out.commit(".orig"));
+
+        assert_eq!(assert_ok!(fs::read_to_string(&path)), "after\n");
+        assert_eq!(assert_ok!(fs::read_to_string(dir.join("edit.txt.orig"))), "before\n");
+        assert_eq!(assert_ok!(fs::read_dir(&dir)).count(), 2);
+        assert_ok!(fs::remove_dir_all(&dir));
+    }
+}
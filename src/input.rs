@@ -0,0 +1,147 @@
+use crate::records::{Records, chomp};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::iter::Peekable;
+
+// Records from a sequence of files, read as one continuous stream
+// Each record keeps its separator, as in Records.
+// Files are opened only when the previous file is exhausted.
+// As in GNU sed, a file which can't be read is reported on stderr and skipped.
+pub struct InputFiles {
+    pending: VecDeque<String>,
+    current: Option<Records<Box<dyn BufRead>>>,
+    separator: u8,
+    failed: bool,
+}
+
+impl InputFiles {
+    // with no file names, read stdin
+    pub fn new(filenames: &[String], separator: u8) -> InputFiles {
+        let mut pending: VecDeque<String> = filenames.iter().cloned().collect();
+        if pending.is_empty() {
+            pending.push_back("-".to_string());
+        }
+        InputFiles { pending, current: None, separator, failed: false }
+    }
+
+    // true if any file could not be read
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+}
+
+pub fn open(filename: &str) -> io::Result<Box<dyn BufRead>> {
+    if filename == "-" {
+        Ok(Box::new(BufReader::new(io::stdin())))
+    } else {
+        let file = File::open(filename)?;
+        if file.metadata()?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Is a directory"))
+        }
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+pub fn warn_unreadable(filename: &str, e: &io::Error) {
+    eprintln!("tsed: can't read {}: {}", filename, e);
+}
+
+impl Iterator for InputFiles {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        loop {
+            if let Some(records) = &mut self.current {
+                match records.next() {
+                    Some(record) => return Some(record),
+                    None => self.current = None,
+                }
+            }
+            let filename = self.pending.pop_front()?;
+            match open(&filename) {
+                Ok(reader) => self.current = Some(Records::new(reader, self.separator)),
+                Err(e) => {
+                    warn_unreadable(&filename, &e);
+                    self.failed = true;
+                }
+            }
+        }
+    }
+}
+
+// numbered lines, with one line of lookahead to recognize the last line
+// lines are returned without their separator
+pub struct Numbered<I: Iterator> {
+    input: Peekable<I>,
+    separator: u8,
+    line_number: u64,
+    terminated: bool,
+}
+
+impl<I: Iterator<Item = io::Result<String>>> Numbered<I> {
+    pub fn new(input: I, separator: u8) -> Numbered<I> {
+        Numbered { input: input.peekable(), separator, line_number: 0, terminated: true }
+    }
+
+    pub fn next_line(&mut self) -> Option<io::Result<String>> {
+        let mut line = match self.input.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        self.line_number += 1;
+        self.terminated = chomp(&mut line, self.separator);
+        Some(Ok(line))
+    }
+
+    // number of the line most recently returned by next_line
+    pub fn line_number(&self) -> u64 {
+        self.line_number
+    }
+
+    // true if no input follows the line most recently returned by next_line
+    pub fn is_last(&mut self) -> bool {
+        self.input.peek().is_none()
+    }
+
+    // false if the line most recently returned by next_line had no separator
+    pub fn terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assert_ok::assert_ok;
+    use std::fs;
+
+    #[test]
+    fn concatenates_and_skips_missing() {
+        let dir = std::env::temp_dir().join(format!("tsed-input-{}", std::process::id()));
+        assert_ok!(fs::create_dir_all(&dir));
+        let a = dir.join("a");
+        let b = dir.join("b");
+        assert_ok!(fs::write(&a, "1\n2\n"));
+        assert_ok!(fs::write(&b, "3\n"));
+        let names: Vec<String> = [&a, &dir.join("missing"), &b].iter().map(|p| p.display().to_string()).collect();
+
+        let mut input = InputFiles::new(&names, b'\n');
+        let lines: Vec<String> = input.by_ref().map(|l| assert_ok!(l)).collect();
+        assert_eq!(lines, ["1\n", "2\n", "3\n"]);
+        assert!(input.failed());
+        assert_ok!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn last_line_without_separator() {
+        let mut input = Numbered::new(Records::new(&b"a\0b"[..], 0), 0);
+        assert_eq!(assert_ok!(input.next_line().unwrap()), "a");
+        assert!(input.terminated());
+        assert_eq!(assert_ok!(input.next_line().unwrap()), "b");
+        assert!(!input.terminated());
+        assert!(input.is_last());
+    }
+}
@@ -1,10 +1,20 @@
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use std::io;
-use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::io::Write;
 
 mod commands;
+mod files;
+mod in_place;
+mod input;
+mod records;
 mod regex;
-use commands::{Command, Function, match_address, parse_command_finish};
+use files::{Appended, ReadFiles, WriteFiles, flush_appended};
+use in_place::InPlaceFile;
+use input::{InputFiles, Numbered};
+use records::{RecordWriter, Records};
+use commands::{Command, Function, RangeState, ScriptError, last_regex, match_address, parse_script_finish, resolve_targets, suppresses_output};
+use crate::regex::Matcher;
 use crate::regex::parser::{Syntax, new_regex_input};
 
 #[derive(Parser, Debug)]
@@ -15,115 +25,366 @@ struct Cli {
     command_or_files: Vec<String>, // s/regex/replacement/
     #[arg(short='e', long="expression")]
     commands: Vec<String>,
+    #[arg(short='f', long="file", help="read commands from a script file")]
+    script_files: Vec<PathBuf>,
     #[arg(short='E', long="regexp-extended", help="posix extended regexp syntax")]
     extended_syntax: bool,
     #[arg(short='T', help="tealsed regexp syntax; default if invoked as tsed")]
     teal_syntax: bool,
     #[arg(long, help="accept some GNU extensions")]
     gnu: bool,
+    #[arg(long, conflicts_with="gnu", help="disable GNU extensions, and match the longest alternative as POSIX requires")]
+    posix: bool,
+    #[arg(long="leftmost-longest", help="match the longest alternative, as with --posix; for instance with -E")]
+    leftmost_longest: bool,
+    #[arg(short='i', long="in-place", value_name="SUFFIX", num_args=0..=1, require_equals=true, default_missing_value="",
+          help="edit files in place, making a backup if SUFFIX is given")]
+    in_place: Option<String>,
+    #[arg(short='s', long="separate", help="consider files as separate rather than as a single continuous stream")]
+    separate: bool,
+    #[arg(short='z', long="null-data", help="separate lines by NUL characters")]
+    null_data: bool,
     #[arg(short='n', long="quiet", help="do not print every line")]
     no_print: bool,
     #[arg(long, help="print intermediate results")]
     debug: bool,
 }
 
-fn run_commands<R>(commands: &[Command], input: R, output: &mut dyn Write, no_print: bool) -> io::Result<()>
+// state which carries over from one input file to the next, even with -s or -i
+// w files are not flushed on drop if the process exits, so call w_files.flush first
+#[derive(Debug, Default)]
+struct Persistent<'a> {
+    hold: String,
+    w_files: WriteFiles,
+    r_files: ReadFiles,
+    last_regex: Option<&'a Matcher>, // used by the empty regex
+}
+
+// how a pass through the script ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CycleEnd {
+    Print, // print the pattern space, unless -n
+    Delete, // start the next cycle without printing
+    Restart, // start the next cycle without printing or reading input
+    Quit(i32), // print as usual, then exit with the given status
+    QuitSilently(i32), // exit without printing
+}
+
+// command line flags which affect how commands run
+#[derive(Clone, Copy, Debug)]
+struct Options {
+    no_print: bool,
+    gnu: bool,
+    posix: bool, // strict POSIX syntax
+    longest: bool, // POSIX leftmost-longest matching, rather than the regex crate's leftmost-first
+    separator: u8, // between input lines, and between lines joined by N, G, and H
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { no_print: false, gnu: false, posix: false, longest: false, separator: b'\n' }
+    }
+}
+
+// run a command with the shell, returning its output without a trailing newline
+fn shell_output(command: &str) -> io::Result<String> {
+    let output = std::process::Command::new("sh").arg("-c").arg(command).output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    if text.ends_with('\n') {
+        text.pop();
+    }
+    Ok(text)
+}
+
+// returns the exit status if q or Q ended the run
+// input lines keep their separator, so a missing separator at the end of input can be reproduced
+fn run_commands<'a, R>(commands: &'a [Command], input: R, output: &mut RecordWriter, options: &Options, persistent: &mut Persistent<'a>) -> io::Result<Option<i32>>
 where R: Iterator<Item = io::Result<String>> {
-    // input buffer, reused for each line
-    let mut buf = String::new();
-    let mut line_number = 0;
 
     // swap the roles of these buffers as we make subsequent replacements
     let mut read = String::new();
     let mut write = String::new();
 
-    let mut hold = String::new();
-
-    // for each command, a boolean to track whether we are within its address range
-    let mut in_matching_range = Vec::with_capacity(commands.len());
-    for _ in commands {
-        in_matching_range.push(false);
-    }
-
-    for r_line in input {
-        let line = r_line?;
-        line_number += 1;
-        read.clear();
-        read.push_str(&line);
-
-        for (cmd_index, cmd) in commands.iter().enumerate() {
-            let should_apply = match (&cmd.start, &cmd.end) {
-                (None, None) => true,
-                (Some(addr), None) => match_address(&addr, &read, line_number),
-                (Some(start), Some(end)) =>
-                    if in_matching_range[cmd_index] {
-                        let stop = match_address(&end, &read, line_number);
-                        in_matching_range[cmd_index] = !stop;
-                        true
-                    } else {
-                        let start = match_address(&start, &read, line_number);
-                        in_matching_range[cmd_index] = !start;
-                        start
-                    },
-                (None, Some(end)) => panic!("end address has no matching start {:?}", end)
-            };
-            if should_apply {
-                use Function::{*};
-
-                match &cmd.function {
-                    Equals => writeln!(output, "{}", line_number).unwrap(),
-                    Fd => {
-                        read.clear();
-                        break;
-                    },
-                    D => {
-                        if let Some(ix) = read.find('\n') {
-                            write.push_str(&read[ix+1..]);
-                            std::mem::swap(&mut read, &mut write);
-                            write.clear();
-                        } else {
+    // for each command, whether we are within its address range
+    let mut ranges: Vec<RangeState> = commands.iter()
+        .map(|cmd| cmd.start.as_ref().map_or(RangeState::Closed, RangeState::new))
+        .collect();
+
+    let mut input = Numbered::new(input, options.separator);
+    let separator = options.separator as char;
+
+    // text from a, r, and R, printed at the end of the cycle or when n or N reads input
+    let mut append_queue = Vec::new();
+
+    // any successful substitution since the last input line or taken t / T branch
+    let mut substituted = false;
+
+    let mut end = CycleEnd::Print;
+    loop {
+        if end != CycleEnd::Restart {
+            match input.next_line() {
+                Some(r_line) => {
+                    let line = r_line?;
+                    read.clear();
+                    read.push_str(&line);
+                    substituted = false;
+                },
+                None => break,
+            }
+        }
+
+        // index of the next command to run
+        let mut pc = 0;
+        end = 'script: {
+            while pc < commands.len() {
+                let cmd_index = pc;
+                let cmd = &commands[pc];
+                pc += 1;
+
+                let should_apply = match (&cmd.start, &cmd.end) {
+                    (None, None) => true,
+                    (Some(addr), None) =>
+                        match_address(addr, &read, input.line_number(), input.is_last(), &mut persistent.last_regex)?,
+                    (Some(start), Some(end)) => ranges[cmd_index].matches(
+                        start, end, &read, input.line_number(), input.is_last(), &mut persistent.last_regex)?,
+                    (None, Some(end)) => panic!("end address has no matching start {:?}", end)
+                } != cmd.negated;
+                if should_apply {
+                    use Function::{*};
+
+                    match &cmd.function {
+                        Equals => writeln!(output, "{}", input.line_number())?,
+                        Label(_) => (),
+                        Block(_) | EndBlock => (),
+                        Fb(jump) => pc = jump.target,
+                        Fd => {
+                            read.clear();
+                            break 'script CycleEnd::Delete;
+                        },
+                        // like d if there is no newline
+                        D => {
+                            if let Some(ix) = read.find(separator) {
+                                write.push_str(&read[ix+1..]);
+                                std::mem::swap(&mut read, &mut write);
+                                write.clear();
+                                break 'script CycleEnd::Restart;
+                            } else {
+                                read.clear();
+                                break 'script CycleEnd::Delete;
+                            }
+                        },
+                        Fg => {
+                            read.clear();
+                            read.push_str(&persistent.hold);
+                        },
+                        G => {
+                            read.push(separator);
+                            read.push_str(&persistent.hold);
+                        },
+                        Fh => {
+                            persistent.hold.clear();
+                            persistent.hold.push_str(&read);
+                        },
+                        H => {
+                            persistent.hold.push(separator);
+                            persistent.hold.push_str(&read);
+                        },
+                        Fa(text) => append_queue.push(Appended::Text(text.clone())),
+                        // with a range, print the text once at the end of the range
+                        Fc(text) => {
+                            if cmd.end.is_none() || cmd.negated || !ranges[cmd_index].is_open() {
+                                writeln!(output, "{}", text)?;
+                            }
                             read.clear();
-                        }
-                    },
-                    Fg => {
-                        read.clear();
-                        read.push_str(&hold);
-                    },
-                    G => {
-                        read.push_str("\n");
-                        read.push_str(&hold);
-                    },
-                    Fh => {
-                        hold.clear();
-                        hold.push_str(&read);
-                    },
-                    H => {
-                        hold.push_str("\n");
-                        hold.push_str(&read);
-                    },
-                    Fi(text) => writeln!(output, "{}", text).unwrap(),
-                    Fp => writeln!(output, "{}", read).unwrap(),
-                    Fs(regex, replacement) => {
-                        // TODO greedy match
-                        let changed = regex::replace(&regex, &read, &mut write, replacement);
-                        if changed {
+                            break 'script CycleEnd::Delete;
+                        },
+                        Fi(text) => writeln!(output, "{}", text)?,
+                        // at the end of input, n quits after the usual end of cycle output
+                        Fn => {
+                            if input.is_last() {
+                                break 'script CycleEnd::Print;
+                            }
+                            if !options.no_print { output.write_record(&read, input.terminated())?; }
+                            flush_appended(&mut append_queue, output)?;
+                            let line = input.next_line().unwrap()?;
+                            read.clear();
+                            read.push_str(&line);
+                            substituted = false;
+                        },
+                        // at the end of input, N quits without printing, except that GNU prints the pattern space
+                        N => {
+                            if input.is_last() {
+                                break 'script if options.gnu { CycleEnd::Print } else { CycleEnd::Delete };
+                            }
+                            flush_appended(&mut append_queue, output)?;
+                            let line = input.next_line().unwrap()?;
+                            read.push(separator);
+                            read.push_str(&line);
+                            substituted = false;
+                        },
+                        P => output.write_record(read.split(separator).next().unwrap(), true)?,
+                        Fp => output.write_record(&read, input.terminated())?,
+                        Fr(path) => append_queue.push(Appended::File(path.clone())),
+                        R(path) => if let Some(line) = persistent.r_files.read_line(path, options.separator) {
+                            append_queue.push(Appended::Record(line));
+                        },
+                        Fq(status) => break 'script CycleEnd::Quit(*status),
+                        Q(status) => break 'script CycleEnd::QuitSilently(*status),
+                        Fs(regex, replacement, flags) => {
+                            let regex = match regex {
+                                Some(regex) => regex,
+                                None => last_regex(persistent.last_regex)?,
+                            };
+                            persistent.last_regex = Some(regex);
+                            let limit = if flags.global { 0 } else { 1 };
+                            let changed = regex::replacen(regex, &read, &mut write, flags.occurrence - 1, limit, replacement);
+                            if changed {
+                                std::mem::swap(&mut read, &mut write);
+                                write.clear();
+                                substituted = true;
+                                if flags.execute {
+                                    read = shell_output(&read)?;
+                                }
+                                if flags.print {
+                                    output.write_record(&read, input.terminated())?;
+                                }
+                                if let Some(path) = &flags.write {
+                                    persistent.w_files.write_record(path, &read, output)?;
+                                }
+                            }
+                        },
+                        Ft(jump) => if substituted {
+                            substituted = false;
+                            pc = jump.target;
+                        },
+                        T(jump) => if substituted {
+                            substituted = false;
+                        } else {
+                            pc = jump.target;
+                        },
+                        Fw(path) => persistent.w_files.write_record(path, &read, output)?,
+                        W(path) => persistent.w_files.write_record(path, read.split(separator).next().unwrap(), output)?,
+                        Fx => std::mem::swap(&mut read, &mut persistent.hold),
+                        Fy(table) => {
+                            table.apply(&read, &mut write);
                             std::mem::swap(&mut read, &mut write);
                             write.clear();
-                        }
-                    },
-                    Fx => std::mem::swap(&mut read, &mut hold),
-                }
+                        },
+                    }
 
+                } else if let Function::Block(end) = &cmd.function {
+                    pc = *end;
+                }
             }
+            CycleEnd::Print
+        };
+        if let CycleEnd::QuitSilently(status) = end {
+            return Ok(Some(status))
+        }
+        if matches!(end, CycleEnd::Print | CycleEnd::Quit(_)) && !options.no_print {
+            output.write_record(&read, input.terminated())?;
+        }
+        flush_appended(&mut append_queue, output)?;
+        if let CycleEnd::Quit(status) = end {
+            return Ok(Some(status))
         }
-        if !no_print { writeln!(output, "{}", read).unwrap(); }
-        buf.clear();
     }
-    Ok(())
+    Ok(None)
+}
+
+// clap can't express GNU's -iSUFFIX, where the suffix must be attached to the flag,
+// so rewrite it as --in-place=SUFFIX, which clap can
+fn expand_in_place_suffix<I: Iterator<Item = String>>(args: I) -> Vec<String> {
+    let mut expanded = Vec::new();
+    let mut options_done = false;
+    for arg in args {
+        if options_done || arg == "--" || arg.starts_with("--") || !arg.starts_with('-') {
+            options_done = options_done || arg == "--";
+            expanded.push(arg);
+            continue;
+        }
+        // a cluster of short flags; anything after e or f is that flag's value
+        let split = arg.char_indices().skip(1)
+            .take_while(|(_, c)| *c != 'e' && *c != 'f')
+            .find(|(_, c)| *c == 'i')
+            .map(|(ix, _)| ix);
+        match split {
+            Some(ix) if ix + 1 < arg.len() => {
+                if ix > 1 {
+                    expanded.push(arg[..ix].to_string());
+                }
+                expanded.push(format!("--in-place={}", &arg[ix+1..]));
+            },
+            _ => expanded.push(arg),
+        }
+    }
+    expanded
+}
+
+// script text from -e, or the name of a script file from -f
+enum ScriptSource<'a> {
+    Expression(&'a str),
+    File(&'a PathBuf),
+}
+
+// POSIX says -e and -f scripts are concatenated in the order they appear on the command line
+fn script_sources<'a>(matches: &ArgMatches, args: &'a Cli) -> Vec<ScriptSource<'a>> {
+    let mut sources = Vec::new();
+    if let Some(indices) = matches.indices_of("commands") {
+        sources.extend(indices.zip(&args.commands).map(|(i, e)| (i, ScriptSource::Expression(e))));
+    }
+    if let Some(indices) = matches.indices_of("script_files") {
+        sources.extend(indices.zip(&args.script_files).map(|(i, f)| (i, ScriptSource::File(f))));
+    }
+    sources.sort_by_key(|(i, _)| *i);
+    sources.into_iter().map(|(_, source)| source).collect()
+}
+
+// the text of the whole script, and its commands
+// POSIX: the pieces from -e and -f are joined by newlines and parsed as one script,
+// so the text of a\, i\, or c\ may follow in the next piece
+fn load_script(sources: &[ScriptSource], syntax: Syntax, options: &Options) -> io::Result<(String, Vec<Command>)> {
+    let mut text = String::new();
+    let mut starts = Vec::new(); // the first line of each piece
+    let mut line = 1;
+    for (i, source) in sources.iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+            line += 1;
+        }
+        starts.push(line);
+        let piece = match source {
+            ScriptSource::Expression(e) => e.to_string(),
+            ScriptSource::File(path) => std::fs::read_to_string(path)?,
+        };
+        line += piece.matches('\n').count() as u32;
+        text.push_str(&piece);
+    }
+    // report parse errors relative to the piece, and to the file it came from
+    let commands = parse_script_text(&text, syntax, options).map_err(|mut e| {
+        let piece = starts.partition_point(|start| *start <= e.line) - 1;
+        e.line -= starts[piece] - 1;
+        match &sources[piece] {
+            ScriptSource::Expression(_) => io::Error::from(e),
+            ScriptSource::File(path) => io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", path.display(), e)),
+        }
+    })?;
+    Ok((text, commands))
+}
+
+fn parse_script_text(text: &str, syntax: Syntax, options: &Options) -> Result<Vec<Command>, ScriptError> {
+    let mut s = new_regex_input(text);
+    s.extra.syntax = syntax;
+    s.extra.gnu = options.gnu;
+    s.extra.posix = options.posix;
+    s.extra.longest = options.longest;
+    s.extra.separator = options.separator;
+    parse_script_finish(s)
 }
 
 fn main() -> io::Result<()> {
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches_from(expand_in_place_suffix(std::env::args()));
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     // one syntax for all regexen in all commands
     let syntax = match (args.extended_syntax, args.teal_syntax) {
@@ -142,28 +403,32 @@ fn main() -> io::Result<()> {
         }
     };
 
-    let commands: Vec<Command> =
-        if args.commands.len() == 0 {
-            match args.command_or_files.first() {
-                Some(arg) => {
-                    let mut s = new_regex_input(&arg);
-                    s.extra.syntax = syntax;
-                    parse_command_finish(s)
-                        .map(|cmd| Vec::from([cmd]))?
-                },
-                None => Vec::new()
-            }
-        } else {
-            args.commands.iter()
-                .map(|cmd| parse_command_finish(new_regex_input(&cmd)))
-                .collect::<io::Result<Vec<Command>>>()?
-        };
+    let mut sources = script_sources(&matches, &args);
+    let script_in_args = sources.is_empty();
+    if script_in_args {
+        if let Some(arg) = args.command_or_files.first() {
+            sources.push(ScriptSource::Expression(arg));
+        }
+    }
+
+    let mut options = Options {
+        no_print: args.no_print,
+        gnu: args.gnu,
+        posix: args.posix,
+        longest: args.posix || args.leftmost_longest,
+        separator: if args.null_data { b'\0' } else { b'\n' },
+    };
+    let (text, mut commands) = load_script(&sources, syntax, &options)?;
+    if suppresses_output(&text) {
+        options.no_print = true;
+    }
+    resolve_targets(&mut commands)?;
 
     if args.debug {
         eprintln!("{:?}", commands)
     }
 
-    let file_args = if args.commands.len() == 0 {
+    let file_args = if script_in_args && !args.command_or_files.is_empty() {
         &args.command_or_files[1..]
     } else {
         &args.command_or_files
@@ -171,19 +436,68 @@ fn main() -> io::Result<()> {
 
     let stdout = io::stdout();
     let mut out_handle = stdout.lock();
+    let mut output = RecordWriter::new(&mut out_handle, options.separator);
 
-    if file_args.len() == 0 {
-        let stdin = io::stdin();
-        let in_handle = stdin.lock();
-        run_commands(&commands, &mut in_handle.lines(), &mut out_handle, args.no_print)?;
-    } else {
+    let mut persistent = Persistent {
+        w_files: WriteFiles::open(&commands)?,
+        ..Persistent::default()
+    };
+    // some input file could not be read
+    let mut failed = false;
+    let mut quit = None;
+
+    if let Some(suffix) = &args.in_place {
+        if file_args.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no input files"))
+        }
         for filename in file_args {
-            let file = std::fs::File::open(filename)?;
-            let buf_reader = io::BufReader::new(file);
-            run_commands(&commands, &mut buf_reader.lines(), &mut out_handle, args.no_print)?;
+            let reader = match input::open(filename) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    input::warn_unreadable(filename, &e);
+                    failed = true;
+                    continue;
+                }
+            };
+            let mut out_file = InPlaceFile::create(Path::new(filename))?;
+            let records = Records::new(reader, options.separator);
+            // as in GNU sed, quitting discards the rest of the file
+            match run_commands(&commands, records, &mut RecordWriter::new(&mut out_file, options.separator), &options, &mut persistent) {
+                Ok(status) => {
+                    out_file.commit(suffix)?;
+                    quit = status;
+                },
+                Err(e) => {
+                    out_file.abort();
+                    return Err(e)
+                }
+            }
+            if quit.is_some() {
+                break;
+            }
+        }
+    } else if args.separate && file_args.len() > 1 {
+        for filename in file_args {
+            let mut input = InputFiles::new(std::slice::from_ref(filename), options.separator);
+            quit = run_commands(&commands, &mut input, &mut output, &options, &mut persistent)?;
+            failed |= input.failed();
+            if quit.is_some() {
+                break;
+            }
         }
+    } else {
+        // all files are one stream, so $ is the last line of the last file
+        let mut input = InputFiles::new(file_args, options.separator);
+        quit = run_commands(&commands, &mut input, &mut output, &options, &mut persistent)?;
+        failed = input.failed();
     }
 
+    persistent.w_files.flush()?;
+    output.flush()?;
+    let status = quit.unwrap_or(if failed { 2 } else { 0 });
+    if status != 0 {
+        std::process::exit(status);
+    }
     Ok(())
 }
 
@@ -192,16 +506,26 @@ pub mod tests {
     use super::*;
     use assert_ok::assert_ok;
 
+    // input lines with their separators
+    fn records(input: &str) -> impl Iterator<Item = io::Result<String>> + '_ {
+        input.lines().map(|l| Ok(format!("{}\n", l)))
+    }
+
     fn test_commands(cmd_strs: &[&str], input: &str, expected: &str) {
-        let r_commands = cmd_strs.iter()
-                .map(|cmd| parse_command_finish(new_regex_input(&cmd)))
-                .collect::<io::Result<Vec<Command>>>();
-        let commands = assert_ok!(r_commands);
-        let mut lines = Vec::new();
-        lines.push(Ok(input.to_owned()));
+        test_syntax(Syntax::Teal, cmd_strs, input, expected)
+    }
+
+    fn test_syntax(syntax: Syntax, cmd_strs: &[&str], input: &str, expected: &str) {
+        test_options(&Options::default(), syntax, cmd_strs, input, expected)
+    }
+
+    fn test_options(options: &Options, syntax: Syntax, cmd_strs: &[&str], input: &str, expected: &str) {
+        let sources: Vec<ScriptSource> = cmd_strs.iter().map(|cmd| ScriptSource::Expression(cmd)).collect();
+        let (_, mut commands) = assert_ok!(load_script(&sources, syntax, options));
+        assert_ok!(resolve_targets(&mut commands));
         let mut output = Vec::new();
-        assert_ok!(
-            run_commands(&commands, lines.into_iter(), &mut output, false));
+        assert_ok!(run_commands(&commands, records(input), &mut RecordWriter::new(&mut output, options.separator),
+                                options, &mut Persistent::default()));
         let mut actual = assert_ok!( String::from_utf8(output) );
         let last = actual.pop();
         assert_eq!(last, Some('\n'));
@@ -221,4 +545,448 @@ pub mod tests {
     fn replace_end() {
         test_one_command("s/$/d/", "foo", "food")
     }
+
+    #[test]
+    fn delete_no_print() {
+        test_commands(&["2d"], "a\nb\nc", "a\nc")
+    }
+
+    #[test]
+    fn branch_skips() {
+        test_commands(&["b end", "s/a/b/", ":end"], "ack", "ack")
+    }
+
+    #[test]
+    fn branch_to_end() {
+        test_commands(&["/x/b", "s/a/b/"], "xa\nya", "xa\nyb")
+    }
+
+    #[test]
+    fn t_loop() {
+        test_commands(&[":a", "s/aa/a/", "ta"], "aaaaaa", "a")
+    }
+
+    #[test]
+    fn t_resets_flag() {
+        test_commands(&["s/a/b/", "ta", ":a", "tb", "s/$/!/", ":b"], "ack", "bck!")
+    }
+
+    #[test]
+    fn big_t_without_substitution() {
+        test_commands(&["s/x/y/", "Tend", "s/$/!/", ":end"], "ab\nxb", "ab\nyb!")
+    }
+
+    #[test]
+    fn negated_delete() {
+        test_commands(&["/^#/!d"], "# a\nb\n# c", "# a\n# c")
+    }
+
+    #[test]
+    fn block() {
+        test_commands(&["/^#/!{", "s/a/b/", "s/$/!/", "}"], "# a\na", "# a\nb!")
+    }
+
+    #[test]
+    fn nested_range_blocks() {
+        test_commands(&["/b/,/d/{", "/c/,/e/{", "s/$/!/", "}", "}"], "a\nb\nc\nd\ne", "a\nb\nc!\nd!\ne")
+    }
+
+    #[test]
+    fn script_with_separators() {
+        test_one_command(":a\ns/aa/a/;ta # squeeze", "aaaa", "a")
+    }
+
+    #[test]
+    fn script_block_one_line() {
+        test_one_command("/^#/!{ s/a/b/; s/$/!/ }", "# a\na", "# a\nb!")
+    }
+
+    #[test]
+    fn scripts_in_argument_order() {
+        let matches = Cli::command().get_matches_from(["tsed", "-e", "p", "-f", "x.sed", "--expression", "d", "--file", "y.sed"]);
+        let args = assert_ok!(Cli::from_arg_matches(&matches));
+        let order: Vec<String> = script_sources(&matches, &args).iter().map(|source| match source {
+            ScriptSource::Expression(e) => e.to_string(),
+            ScriptSource::File(f) => f.display().to_string(),
+        }).collect();
+        assert_eq!(order, ["p", "x.sed", "d", "y.sed"]);
+    }
+
+    #[test]
+    fn script_file_error_line() {
+        let path = std::env::temp_dir().join(format!("tsed-script-{}.sed", std::process::id()));
+        assert_ok!(std::fs::write(&path, "#!/usr/bin/sed -f\ns/a/b/\nk\n"));
+        let sources = [ScriptSource::Expression("p\np"), ScriptSource::File(&path), ScriptSource::Expression("p")];
+        let error = load_script(&sources, Syntax::Basic, &Options::default()).unwrap_err();
+        assert_ok!(std::fs::remove_file(&path));
+        assert_eq!(error.to_string(), format!("{}: line 3, column 1: cannot parse \"k\"", path.display()));
+    }
+
+    #[test]
+    fn text_continues_in_next_piece() {
+        test_commands(&["2a\\", "hello"], "1\n2\n3", "1\n2\nhello\n3");
+        test_commands(&["1i\\", "  x\\", "y", "p"], "a", "  x\ny\na\na");
+    }
+
+    fn expand(args: &[&str]) -> Vec<String> {
+        expand_in_place_suffix(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn in_place_suffix() {
+        assert_eq!(expand(&["tsed", "-i.bak", "p"]), ["tsed", "--in-place=.bak", "p"]);
+        assert_eq!(expand(&["tsed", "-ni", "p"]), ["tsed", "-ni", "p"]);
+        assert_eq!(expand(&["tsed", "-ni~", "p"]), ["tsed", "-n", "--in-place=~", "p"]);
+        assert_eq!(expand(&["tsed", "-es/i/x/", "--", "-ix"]), ["tsed", "-es/i/x/", "--", "-ix"]);
+    }
+
+    #[test]
+    fn last_line() {
+        test_one_command("$s/$/!/", "a\nb\nc", "a\nb\nc!")
+    }
+
+    #[test]
+    fn not_last_line() {
+        test_one_command("$!d", "a\nb\nc", "c")
+    }
+
+    #[test]
+    fn hold_persists_between_runs() {
+        let mut commands = assert_ok!(parse_script_finish(new_regex_input("$!d;x")));
+        assert_ok!(resolve_targets(&mut commands));
+        let mut persistent = Persistent::default();
+        let mut output = Vec::new();
+        for file in ["a\nb", "c\nd"] {
+            assert_ok!(run_commands(&commands, records(file), &mut RecordWriter::new(&mut output, b'\n'),
+                                    &Options::default(), &mut persistent));
+        }
+        assert_eq!(assert_ok!(String::from_utf8(output)), "\nb\n");
+    }
+
+    #[test]
+    fn replace_global() {
+        test_one_command("s/a/b/g", "banana", "bbnbnb")
+    }
+
+    #[test]
+    fn replace_nth() {
+        test_one_command("s/a/b/2", "banana", "banbna")
+    }
+
+    #[test]
+    fn replace_nth_global() {
+        test_one_command("s/a/b/2g", "banana", "banbnb")
+    }
+
+    #[test]
+    fn replace_print() {
+        test_one_command("s/a/b/p", "a\nc", "b\nb\nc")
+    }
+
+    #[test]
+    fn replace_case_insensitive() {
+        test_one_command("s/A/b/gI", "aA", "bb")
+    }
+
+    #[test]
+    fn replace_multi_line() {
+        test_one_command("G;s/^/>/Mg", "a", ">a\n>")
+    }
+
+    #[test]
+    fn replace_execute() {
+        test_one_command("s/x/echo y/e", "x", "y")
+    }
+
+    #[test]
+    fn replace_whole_match() {
+        test_syntax(Syntax::Extended, &[r"s/[0-9]+/<&>/g"], "a1b22", "a<1>b<22>")
+    }
+
+    #[test]
+    fn replace_case_conversion() {
+        test_syntax(Syntax::Extended, &[r"s/(h)([a-z]+)/\U\1\E\u\2/g"], "hello world", "HEllo world")
+    }
+
+    #[test]
+    fn replace_basic_group() {
+        test_syntax(Syntax::Basic, &[r"s/\(a\)(b)/\1x/"], "a(b) ab", "ax ab")
+    }
+
+    #[test]
+    fn basic_backreference() {
+        test_syntax(Syntax::Basic, &[r"/^\(.\)\1/d", r"s/\(.\)\1/<&>/g"], "aab\nabbcc", "a<bb><cc>")
+    }
+
+    #[test]
+    fn class_newline_escape() {
+        test_syntax(Syntax::Basic, &[r"N;s/[^\n]*$/X/"], "ab\ncd", "ab\nX");
+        let options = Options { posix: true, ..Options::default() };
+        test_options(&options, Syntax::Basic, &[r"N;s/[^\n]*$/X/"], "ab\ncd", "X");
+    }
+
+    #[test]
+    fn groups_numbered_per_regex() {
+        test_syntax(Syntax::Basic, &[r"s/\(a\)/x/;s/\(b\)\1/<\1>/"], "bb", "<b>");
+        test_syntax(Syntax::Basic, &[r"/\(a\)/d;s/\(b\)\1/<\1>/"], "bb", "<b>");
+    }
+
+    #[test]
+    fn escaped_delimiter_literal() {
+        let options = Options { gnu: true, ..Options::default() };
+        test_options(&options, Syntax::Basic, &[r"s|a\|b|X|"], "a|b\nab", "X\nab");
+        test_options(&options, Syntax::Basic, &[r"s{a\{2}{X{"], "a{2}aa", "Xaa");
+        test_syntax(Syntax::Extended, &[r"s|a\|b|X|"], "a|b\nab", "X\nab");
+        test_syntax(Syntax::Teal, &[r"s(\(a\)(X("], "(a)", "X");
+    }
+
+    #[test]
+    fn basic_gnu_alternation() {
+        let options = Options { gnu: true, ..Options::default() };
+        test_options(&options, Syntax::Basic, &[r"s/a\|b/x/g"], "abc a|b", "xxc x|x")
+    }
+
+    #[test]
+    fn leftmost_longest() {
+        test_syntax(Syntax::Extended, &["s/a|ab/X/"], "ab", "Xb");
+        let options = Options { longest: true, ..Options::default() };
+        test_options(&options, Syntax::Extended, &["s/a|ab/X/"], "ab", "X");
+        test_options(&options, Syntax::Basic, &[r"s/\(a*\)\1/<&>/"], "aaa", "<aa>a");
+        test_options(&options, Syntax::Basic, &[r"s/\(a\)/x/;s/\(b\)/<\1>/"], "ab", "x<b>");
+        test_options(&options, Syntax::Teal, &["s/(?P<x>a)/[${x}]/"], "abc", "[a]bc");
+    }
+
+    #[test]
+    fn append_insert() {
+        test_commands(&["2a after", "2i\\\nbefore"], "a\nb\nc", "a\nbefore\nb\nafter\nc")
+    }
+
+    #[test]
+    fn append_after_delete() {
+        test_one_command("a\\\n  x\\\ny\nd", "a", "  x\ny")
+    }
+
+    #[test]
+    fn change_range() {
+        test_one_command("2,3c\\\nchanged", "a\nb\nc\nd", "a\nchanged\nd")
+    }
+
+    #[test]
+    fn change_each_line() {
+        test_one_command("/b/c x", "a\nb\nb", "a\nx\nx")
+    }
+
+    #[test]
+    fn next_prints_and_replaces() {
+        test_one_command("n;d", "1\n2\n3\n4\n5", "1\n3\n5")
+    }
+
+    #[test]
+    fn next_quiet() {
+        test_options(&Options { no_print: true, ..Options::default() }, Syntax::Teal, &["n;p"], "1\n2\n3", "2")
+    }
+
+    #[test]
+    fn next_appends() {
+        test_one_command("N;s/\\n/-/", "1\n2\n3\n4", "1-2\n3-4")
+    }
+
+    #[test]
+    fn next_append_at_end_posix() {
+        test_one_command("N;s/\\n/-/", "1\n2\n3", "1-2")
+    }
+
+    #[test]
+    fn next_append_at_end_gnu() {
+        test_options(&Options { gnu: true, ..Options::default() }, Syntax::Teal, &["N;s/\\n/-/"], "1\n2\n3", "1-2\n3")
+    }
+
+    #[test]
+    fn join_continuation_lines() {
+        test_one_command(r":a;/\\$/N;s/\\\n//;ta", "a\\\nb\\\nc\nd", "abc\nd")
+    }
+
+    #[test]
+    fn append_flushed_before_next() {
+        test_one_command("1a x\n1N", "1\n2", "x\n1\n2")
+    }
+
+    #[test]
+    fn print_first_line() {
+        test_one_command("N;P;d", "1\n2\n3\n4", "1\n3")
+    }
+
+    #[test]
+    fn sliding_window() {
+        test_one_command("$!N;P;D", "1\n2\n3", "1\n2\n3")
+    }
+
+    #[test]
+    fn last_two_lines() {
+        test_one_command("$!N;$!D", "1\n2\n3\n4", "3\n4")
+    }
+
+    #[test]
+    fn quit() {
+        test_one_command("2q", "1\n2\n3", "1\n2")
+    }
+
+    #[test]
+    fn quit_prints_appended_text() {
+        test_one_command("a x\nq", "1\n2", "1\nx")
+    }
+
+    #[test]
+    fn quit_silently() {
+        test_one_command("a x\n2Q", "1\n2\n3", "1\nx")
+    }
+
+    #[test]
+    fn quit_status() {
+        let mut commands = assert_ok!(parse_script_finish(new_regex_input("/stop/q 5")));
+        assert_ok!(resolve_targets(&mut commands));
+        let mut output = Vec::new();
+        let status = assert_ok!(run_commands(&commands, records("go\nstop\ngo"), &mut RecordWriter::new(&mut output, b'\n'),
+                                             &Options::default(), &mut Persistent::default()));
+        assert_eq!(status, Some(5));
+        assert_eq!(assert_ok!(String::from_utf8(output)), "go\nstop\n");
+    }
+
+    #[test]
+    fn transliterate() {
+        test_one_command("y/abc/xyz/", "aabbcc", "xxyyzz")
+    }
+
+    #[test]
+    fn transliterate_newline() {
+        test_one_command(r"N;y/\n/,/", "a\nb", "a,b")
+    }
+
+    #[test]
+    fn write_stdout() {
+        test_one_command("w /dev/stdout\nW /dev/stdout", "a", "a\na\na")
+    }
+
+    #[test]
+    fn read_file_after_cycle() {
+        let path = std::env::temp_dir().join(format!("tsed-r-{}", std::process::id()));
+        assert_ok!(std::fs::write(&path, "x\ny\n"));
+        let script = format!("1r {}\n1a z\n2R {}\n3R {}\n", path.display(), path.display(), path.display());
+        test_one_command(&script, "1\n2\n3\n4", "1\nx\ny\nz\n2\nx\n3\ny\n4");
+        assert_ok!(std::fs::remove_file(&path));
+    }
+
+    #[test]
+    fn step_address() {
+        test_one_command("0~3d", "1\n2\n3\n4\n5\n6\n7", "1\n2\n4\n5\n7")
+    }
+
+    #[test]
+    fn relative_range() {
+        test_one_command("/x/,+1d", "a\nx\nb\nc\nx\nd", "a\nc")
+    }
+
+    #[test]
+    fn multiple_range() {
+        test_one_command("2,~4s/$/!/", "1\n2\n3\n4\n5", "1\n2!\n3!\n4!\n5")
+    }
+
+    #[test]
+    fn multiple_range_at_multiple() {
+        test_one_command("4,~4s/$/!/", "1\n2\n3\n4\n5", "1\n2\n3\n4!\n5")
+    }
+
+    #[test]
+    fn zero_range() {
+        test_one_command("0,/x/s/$/!/", "x\nx\ny", "x!\nx\ny")
+    }
+
+    #[test]
+    fn one_range() {
+        test_one_command("1,/x/s/$/!/", "x\nx\ny", "x!\nx!\ny")
+    }
+
+    #[test]
+    fn case_insensitive_range() {
+        test_one_command("/error/I,/^$/d", "ok\nError: x\ndetail\n\nok", "ok\nok")
+    }
+
+    #[test]
+    fn empty_regex_after_address() {
+        test_one_command("/a+/s//x/g", "baac\nbc\naba", "bxc\nbc\nxbx")
+    }
+
+    #[test]
+    fn empty_regex_after_substitute() {
+        test_one_command("s/b/c/\n//d", "ab\nbb", "ac")
+    }
+
+    #[test]
+    fn empty_regex_is_last_used() {
+        // the regex last tried at run time, not the one before it in the script
+        test_one_command("/x/b end\ns/a/A/\n:end\ns//-/", "xa\nya", "-a\nyA")
+    }
+
+    #[test]
+    fn empty_regex_without_previous() {
+        let mut commands = assert_ok!(parse_script_finish(new_regex_input("s//x/")));
+        assert_ok!(resolve_targets(&mut commands));
+        let mut output = Vec::new();
+        let result = run_commands(&commands, records("a"), &mut RecordWriter::new(&mut output, b'\n'),
+                                  &Options::default(), &mut Persistent::default());
+        assert!(result.is_err());
+    }
+
+    fn test_records(options: &Options, script: &str, input: &[u8], expected: &[u8]) {
+        let mut commands = assert_ok!(parse_script_text(script, Syntax::Teal, options));
+        assert_ok!(resolve_targets(&mut commands));
+        let mut output = Vec::new();
+        assert_ok!(run_commands(&commands, Records::new(input, options.separator),
+                                &mut RecordWriter::new(&mut output, options.separator), options, &mut Persistent::default()));
+        assert_eq!(output, expected);
+    }
+
+    // output to a pipe whose reader has exited
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn text_output_errors() {
+        for script in ["c x", "i x", "="] {
+            let mut commands = assert_ok!(parse_script_text(script, Syntax::Teal, &Options::default()));
+            assert_ok!(resolve_targets(&mut commands));
+            let result = run_commands(&commands, records("a"), &mut RecordWriter::new(&mut ClosedPipe, b'\n'),
+                                      &Options::default(), &mut Persistent::default());
+            assert!(result.is_err(), "{}", script);
+        }
+    }
+
+    #[test]
+    fn missing_final_newline() {
+        test_records(&Options::default(), "p", b"a\nb", b"a\na\nb\nb");
+    }
+
+    #[test]
+    fn null_data() {
+        let options = Options { separator: b'\0', ..Options::default() };
+        test_records(&options, "N;P;s/\0/-/", b"a\nb\0c\0", b"a\nb\0a\nb-c\0");
+        test_records(&options, "$!d", b"a\0b", b"b");
+    }
+
+    #[test]
+    fn null_data_multi_line_anchors() {
+        let options = Options { separator: b'\0', ..Options::default() };
+        test_records(&options, "N;s/^/>/Mg;s/$/</Mg", b"a\nb\0c\0", b">a\nb<\0>c<\0");
+        let options = Options { longest: true, ..options };
+        test_records(&options, "N;s/^/>/Mg;s/$/</Mg", b"a\nb\0c\0", b">a\nb<\0>c<\0");
+    }
 }
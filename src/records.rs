@@ -0,0 +1,119 @@
+use std::io;
+use std::io::{BufRead, Write};
+
+// Records read from a stream, each ending with the separator
+// The separator is kept, so the last record shows whether the input ended with one.
+#[derive(Debug)]
+pub struct Records<B> {
+    reader: B,
+    separator: u8,
+}
+
+impl<B: BufRead> Records<B> {
+    pub fn new(reader: B, separator: u8) -> Records<B> {
+        Records { reader, separator }
+    }
+}
+
+impl<B: BufRead> Iterator for Records<B> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let mut buf = Vec::new();
+        match self.reader.read_until(self.separator, &mut buf) {
+            Ok(0) => None,
+            Ok(_) => Some(String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// remove the separator from the end of a record, returning false if it had none
+pub fn chomp(record: &mut String, separator: u8) -> bool {
+    let terminated = record.as_bytes().last() == Some(&separator);
+    if terminated {
+        record.pop();
+    }
+    terminated
+}
+
+// Output of records, each followed by the separator
+// As in GNU sed, a record read without a separator is written without one,
+// unless anything else is written after it.
+pub struct RecordWriter<'a> {
+    output: &'a mut dyn Write,
+    separator: u8,
+    missing_separator: bool,
+}
+
+impl<'a> RecordWriter<'a> {
+    pub fn new(output: &'a mut dyn Write, separator: u8) -> RecordWriter<'a> {
+        RecordWriter { output, separator, missing_separator: false }
+    }
+
+    pub fn separator(&self) -> u8 {
+        self.separator
+    }
+
+    fn supply_separator(&mut self) -> io::Result<()> {
+        if self.missing_separator {
+            self.missing_separator = false;
+            self.output.write_all(&[self.separator])?;
+        }
+        Ok(())
+    }
+
+    // terminated is false for a record which was read without a separator
+    pub fn write_record(&mut self, record: &str, terminated: bool) -> io::Result<()> {
+        self.supply_separator()?;
+        self.output.write_all(record.as_bytes())?;
+        if terminated {
+            self.output.write_all(&[self.separator])
+        } else {
+            self.missing_separator = true;
+            Ok(())
+        }
+    }
+}
+
+// other output, such as the text of a, i, and c, is written as is
+impl Write for RecordWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.supply_separator()?;
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assert_ok::assert_ok;
+
+    #[test]
+    fn null_separated() {
+        let records: Vec<String> = Records::new(&b"a\nb\0c"[..], 0).map(|r| assert_ok!(r)).collect();
+        assert_eq!(records, ["a\nb\0", "c"]);
+    }
+
+    #[test]
+    fn chomp_separator() {
+        let mut record = "a\0".to_string();
+        assert!(chomp(&mut record, 0));
+        assert_eq!(record, "a");
+        assert!(!chomp(&mut record, 0));
+        assert_eq!(record, "a");
+    }
+
+    #[test]
+    fn missing_separator_supplied_by_later_output() {
+        let mut output = Vec::new();
+        let mut writer = RecordWriter::new(&mut output, b'\n');
+        assert_ok!(writer.write_record("a", false));
+        assert_ok!(writer.write_record("a", false));
+        assert_eq!(output, b"a\na");
+    }
+}
@@ -1,55 +1,237 @@
+#[cfg(test)]
 pub mod equivalent;
 
+pub mod backtrack;
+
+pub mod longest;
+
 pub mod parser;
 
-use ::regex::{Regex, Replacer};
+pub mod replacement;
 
-// return true if any replacement was made
-// this is regex::Regex::replacen, except using DoubleString instead of Cow to make applying multiple replacements to a single string efficient
-pub fn replacen<R: Replacer>(regex: &Regex, source: &str, destination: &mut String, limit: usize, mut rep: R) -> bool {
-    // If we know that the replacement doesn't have any capture expansions,
-    // then we can use the fast path.
-    if let Some(rep) = rep.no_expansion() {
-        let mut it = regex.find_iter(source).enumerate().peekable();
-        if it.peek().is_none() {
-            return false; // no change to buffers
+use crate::regex::replacement::Replacement;
+
+use ::regex::{Captures, Regex, RegexBuilder};
+use regex_syntax::ast::Ast;
+
+// the text of each group of one match, from either matcher
+pub trait Groups {
+    fn group(&self, i: usize) -> Option<&str>; // 0 is the whole match
+    fn name(&self, name: &str) -> Option<&str>;
+}
+
+impl Groups for Captures<'_> {
+    fn group(&self, i: usize) -> Option<&str> {
+        self.get(i).map(|m| m.as_str())
+    }
+
+    fn name(&self, name: &str) -> Option<&str> {
+        Captures::name(self, name).map(|m| m.as_str())
+    }
+}
+
+// a compiled pattern
+// The regex crate can't match backreferences, so patterns with them use the slower backtracking matcher.
+// It only finds the first alternative which matches, so when the longest match is wanted,
+// other patterns use its automata to find the longest.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    Fast(Regex),
+    Longest(longest::Regex),
+    Backtrack(backtrack::Regex),
+}
+
+impl Matcher {
+    // with multi_line, ^ and $ also match next to the separator, which is NUL under -z
+    pub fn new(ast: &Ast, case_insensitive: bool, multi_line: bool, longest: bool, separator: u8) -> Result<Matcher, String> {
+        if parser::has_backreference(ast) {
+            backtrack::Regex::new(ast, case_insensitive, multi_line, longest, separator as char).map(Matcher::Backtrack)
+        } else if longest {
+            longest::Regex::new(&format!("{}", ast), case_insensitive, multi_line, separator).map(Matcher::Longest)
+        } else {
+            RegexBuilder::new(&format!("{}", ast))
+                .case_insensitive(case_insensitive)
+                .multi_line(multi_line)
+                .line_terminator(separator)
+                .build()
+                .map(Matcher::Fast)
+                .map_err(|e| e.to_string())
         }
-        let mut last_match = 0;
-        for (i, m) in it {
-            if limit > 0 && i >= limit {
-                break
-            }
-            destination.push_str(&source[last_match..m.start()]);
-            destination.push_str(&rep);
-            last_match = m.end();
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Fast(regex) => regex.is_match(text),
+            Matcher::Longest(regex) => regex.is_match(text),
+            Matcher::Backtrack(regex) => regex.is_match(text),
         }
-        destination.push_str(&source[last_match..]);
-        return true;
     }
-    // The slower path, if the replacement needs access to capture groups.
-    let mut it = regex.captures_iter(source).enumerate().peekable();
-    if it.peek().is_none() {
-        return false;
+}
+
+// return true if any replacement was made
+// this is regex::Regex::replacen, except using DoubleString instead of Cow to make applying multiple replacements to a single string efficient
+// the first skip matches are left unchanged, then limit counts replacements; 0 replaces all later matches
+// destination is left unchanged if no replacement was made
+pub fn replacen(matcher: &Matcher, source: &str, destination: &mut String, skip: usize, limit: usize, rep: &Replacement) -> bool {
+    match matcher {
+        // If we know that the replacement doesn't have any capture expansions,
+        // then we can use the fast path.
+        Matcher::Fast(regex) => match rep.literal() {
+            Some(text) => replace_matches(regex.find_iter(source), |m| (m.start(), m.end()), |_, dst| dst.push_str(text),
+                                          source, destination, skip, limit),
+            // The slower path, if the replacement needs access to capture groups.
+            // unwrap on 0 is OK because captures only reports matches
+            None => replace_matches(regex.captures_iter(source), |cap| { let m = cap.get(0).unwrap(); (m.start(), m.end()) },
+                                    |cap, dst| rep.append(cap, dst), source, destination, skip, limit),
+        },
+        Matcher::Longest(regex) => replace_matches(regex.captures_iter(source), |cap| cap.range(),
+                                                   |cap, dst| rep.append(cap, dst), source, destination, skip, limit),
+        Matcher::Backtrack(regex) => replace_matches(regex.captures_iter(source), |cap| cap.range(),
+                                                     |cap, dst| rep.append(cap, dst), source, destination, skip, limit),
     }
+}
+
+// replacen for the matches from either matcher, with range giving the start and end of each match
+fn replace_matches<M>(matches: impl Iterator<Item = M>, range: impl Fn(&M) -> (usize, usize), mut expand: impl FnMut(&M, &mut String),
+                      source: &str, destination: &mut String, skip: usize, limit: usize) -> bool {
+    let original_len = destination.len();
+    let mut replaced = false;
     let mut last_match = 0;
-    for (i, cap) in it {
-        if limit > 0 && i >= limit {
-            break;
+    for (i, m) in matches.enumerate() {
+        if i < skip {
+            continue
+        }
+        if limit > 0 && i >= skip + limit {
+            break
         }
-        // unwrap on 0 is OK because captures only reports matches
-        let m = cap.get(0).unwrap();
-        destination.push_str(&source[last_match..m.start()]);
-        rep.replace_append(&cap, destination);
-        last_match = m.end();
+        let (start, end) = range(&m);
+        destination.push_str(&source[last_match..start]);
+        expand(&m, destination);
+        last_match = end;
+        replaced = true;
+    }
+    if !replaced {
+        destination.truncate(original_len);
+        return false; // no change to buffers
     }
     destination.push_str(&source[last_match..]);
-    return true;
+    true
 }
 
-pub fn replace<R: Replacer>(regex: &Regex, source: &str, destination: &mut String, rep: R) -> bool {
-    replacen(&regex, source, destination, 1, rep)
-}
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::regex::parser::{Syntax, new_regex_input};
+    use assert_ok::assert_ok;
 
-pub fn replace_all<R: Replacer>(regex: &Regex, source: &str, destination: &mut String, rep: R) -> bool {
-    replacen(&regex, source, destination, 0, rep)
+    fn replaced(pattern: &str, source: &str, skip: usize, limit: usize) -> Option<String> {
+        let matcher = Matcher::Fast(Regex::new(pattern).unwrap());
+        let rep = Replacement { pieces: vec![replacement::Piece::Literal("X".to_string())] };
+        let mut destination = String::new();
+        if replacen(&matcher, source, &mut destination, skip, limit, &rep) {
+            Some(destination)
+        } else {
+            assert_eq!(destination, "");
+            None
+        }
+    }
+
+    #[test]
+    fn replace_second() {
+        assert_eq!(replaced("a", "aaaa", 1, 1), Some("aXaa".to_string()));
+    }
+
+    #[test]
+    fn replace_second_and_later() {
+        assert_eq!(replaced("a", "aaaa", 1, 0), Some("aXXX".to_string()));
+    }
+
+    #[test]
+    fn replace_past_last_match() {
+        assert_eq!(replaced("a", "aa", 2, 0), None);
+    }
+
+    fn basic(pattern: &str, replacement: &str) -> (Matcher, Replacement) {
+        let mut s = new_regex_input(pattern);
+        s.extra.syntax = Syntax::Basic;
+        let (_, ast) = assert_ok!(parser::parse('/', s));
+        let mut s = new_regex_input(replacement);
+        s.extra.syntax = Syntax::Basic;
+        let (_, rep) = assert_ok!(replacement::parse('/', s));
+        (assert_ok!(Matcher::new(&ast, false, false, false, b'\n')), rep)
+    }
+
+    fn replace_all(pattern: &str, replacement: &str, source: &str) -> String {
+        let (matcher, rep) = basic(pattern, replacement);
+        let mut destination = String::new();
+        if replacen(&matcher, source, &mut destination, 0, 0, &rep) {
+            destination
+        } else {
+            source.to_string()
+        }
+    }
+
+    #[test]
+    fn only_backreferences_backtrack() {
+        assert!(matches!(basic(r"\(a\)b", "").0, Matcher::Fast(_)));
+        assert!(matches!(basic(r"\(a\)\1", "").0, Matcher::Backtrack(_)));
+    }
+
+    #[test]
+    fn longest_backtracks_only_for_backreferences() {
+        let longest = |pattern| {
+            let mut s = new_regex_input(pattern);
+            s.extra.syntax = Syntax::Basic;
+            let (_, ast) = assert_ok!(parser::parse('/', s));
+            assert_ok!(Matcher::new(&ast, false, false, true, b'\n'))
+        };
+        assert!(matches!(longest(r"\(a\)b"), Matcher::Longest(_)));
+        assert!(matches!(longest(r"\(a\)\1"), Matcher::Backtrack(_)));
+    }
+
+    #[test]
+    fn replace_with_backreference() {
+        assert_eq!(replace_all(r"\([a-z][a-z]*\) \1", r"[\1]", "hello hello world"), "[hello] world");
+    }
+
+    #[test]
+    fn empty_matches_as_regex_crate() {
+        // \(x*\)\1 matches an even number of x, and is matched by the backtracking matcher
+        assert_eq!(replace_all("x*", "-", "abxxc"), "-a-b-c-");
+        assert_eq!(replace_all(r"\(x*\)\1", "-", "abxxc"), "-a-b-c-");
+    }
+
+    // the first match, marked with <>
+    fn first_match(pattern: &str, source: &str, longest: bool) -> String {
+        let mut s = new_regex_input(pattern);
+        s.extra.syntax = Syntax::Extended;
+        let (_, ast) = assert_ok!(parser::parse('/', s));
+        let matcher = assert_ok!(Matcher::new(&ast, false, false, longest, b'\n'));
+        let rep = Replacement { pieces: vec![
+            replacement::Piece::Literal("<".to_string()),
+            replacement::Piece::Group(0),
+            replacement::Piece::Literal(">".to_string()),
+        ] };
+        let mut destination = String::new();
+        assert!(replacen(&matcher, source, &mut destination, 0, 1, &rep));
+        destination
+    }
+
+    // pattern, text, leftmost-first match as the regex crate, leftmost-longest match as POSIX
+    const LONGEST_CONFORMANCE: [(&str, &str, &str, &str); 6] = [
+        ("a|ab", "ab", "<a>b", "<ab>"),
+        ("one|oneself", "oneself", "<one>self", "<oneself>"),
+        ("(ab|a)(c|bcd)", "abcd", "<abc>d", "<abcd>"),
+        ("x*|y+", "yy", "<>yy", "<yy>"),
+        ("(a|ab)*c", "abac", "<abac>", "<abac>"), // backtracking finds the same match
+        ("b|ab", "xab", "x<ab>", "x<ab>"), // the leftmost match wins, even if shorter
+    ];
+
+    #[test]
+    fn leftmost_longest_conformance() {
+        for (pattern, text, first, longest) in LONGEST_CONFORMANCE {
+            assert_eq!(first_match(pattern, text, false), first, "leftmost-first {} on {}", pattern, text);
+            assert_eq!(first_match(pattern, text, true), longest, "leftmost-longest {} on {}", pattern, text);
+        }
+    }
 }
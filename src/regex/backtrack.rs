@@ -0,0 +1,548 @@
+use crate::regex::Groups;
+use crate::regex::parser::backreference_number;
+
+use regex_syntax::ast::{AssertionKind, Ast, Class, ClassAsciiKind, ClassSet, ClassSetItem, GroupKind, RepetitionKind, RepetitionRange};
+use std::collections::HashMap;
+
+// A backtracking matcher, for the patterns the regex crate can't match: those with backreferences
+// The AST is compiled to a program in the style of https://swtch.com/~rsc/regexp/regexp2.html,
+// which is run with an explicit stack rather than by recursion.
+// Like the regex crate, the first alternative which matches is preferred, unless longest is set.
+// Then, as in POSIX, the longest of the matches starting at the leftmost position is chosen,
+// although the groups are those of the first way found to match it, rather than the POSIX rules for subexpressions.
+
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any, // any character except the line terminator, as . in the regex crate
+    Class(CharClass),
+    Assert(AssertionKind),
+    Save(usize), // record the position in a group's slot
+    Mark(usize), // record the position at the start of an iteration of a loop
+    Progress(usize), // fail unless the position has moved since the loop's Mark
+    Split(usize, usize), // try the first, then the second
+    Jump(usize),
+    Backref(usize), // group number
+    Match,
+}
+
+#[derive(Clone, Debug)]
+struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl CharClass {
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|(a, z)| *a <= c && c <= *z)
+    }
+}
+
+// the same ranges as the regex crate uses for [:name:]
+fn ascii_ranges(kind: &ClassAsciiKind) -> &'static [(char, char)] {
+    use ClassAsciiKind::*;
+    match kind {
+        Alnum => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        Alpha => &[('A', 'Z'), ('a', 'z')],
+        Ascii => &[('\0', '\x7f')],
+        Blank => &[('\t', '\t'), (' ', ' ')],
+        Cntrl => &[('\0', '\x1f'), ('\x7f', '\x7f')],
+        Digit => &[('0', '9')],
+        Graph => &[('!', '~')],
+        Lower => &[('a', 'z')],
+        Print => &[(' ', '~')],
+        Punct => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        Space => &[('\t', '\r'), (' ', ' ')],
+        Upper => &[('A', 'Z')],
+        Word => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        Xdigit => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+    }
+}
+
+fn unsupported(what: &str) -> String {
+    format!("{} is not supported with backreferences", what)
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+    groups: usize, // highest group number
+    names: HashMap<String, usize>, // the number of each named group
+    loops: usize,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> usize {
+        self.program.push(inst);
+        self.program.len() - 1
+    }
+
+    fn compile(&mut self, ast: &Ast) -> Result<(), String> {
+        match ast {
+            Ast::Empty(_) => (),
+            Ast::Flags(_) => return Err(unsupported("setting flags")),
+            Ast::Literal(literal) => { self.push(Inst::Char(literal.c)); },
+            Ast::Dot(_) => { self.push(Inst::Any); },
+            Ast::Assertion(assertion) => match assertion.kind {
+                AssertionKind::StartLine | AssertionKind::EndLine | AssertionKind::StartText | AssertionKind::EndText =>
+                    { self.push(Inst::Assert(assertion.kind.clone())); },
+                _ => return Err(unsupported("a word boundary")),
+            },
+            Ast::Class(Class::Bracketed(bracketed)) => {
+                let mut ranges = Vec::new();
+                match &bracketed.kind {
+                    ClassSet::Item(item) => class_ranges(item, &mut ranges)?,
+                    ClassSet::BinaryOp(_) => return Err(unsupported("a class set operation")),
+                }
+                self.push(Inst::Class(CharClass { ranges, negated: bracketed.negated }));
+            },
+            Ast::Class(_) => return Err(unsupported("a Perl or Unicode class")),
+            Ast::Repetition(repetition) => {
+                let (min, max) = match &repetition.op.kind {
+                    RepetitionKind::ZeroOrOne => (0, Some(1)),
+                    RepetitionKind::ZeroOrMore => (0, None),
+                    RepetitionKind::OneOrMore => (1, None),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => (*n, Some(*n)),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (*n, None),
+                    RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => (*m, Some(*n)),
+                };
+                self.repeat(&repetition.ast, min, max, repetition.greedy)?;
+            },
+            Ast::Group(group) => {
+                if let Some(n) = backreference_number(group) {
+                    self.push(Inst::Backref(n));
+                    return Ok(())
+                }
+                let index = match &group.kind {
+                    GroupKind::CaptureIndex(i) => Some(*i as usize),
+                    GroupKind::CaptureName(name) => {
+                        self.names.insert(name.name.clone(), name.index as usize);
+                        Some(name.index as usize)
+                    },
+                    GroupKind::NonCapturing(flags) if flags.items.is_empty() => None,
+                    GroupKind::NonCapturing(_) => return Err(unsupported("setting flags")),
+                };
+                match index {
+                    Some(i) => {
+                        self.groups = self.groups.max(i);
+                        self.push(Inst::Save(2 * i));
+                        self.compile(&group.ast)?;
+                        self.push(Inst::Save(2 * i + 1));
+                    },
+                    None => self.compile(&group.ast)?,
+                }
+            },
+            Ast::Alternation(alternation) => {
+                // each alternative but the last is tried by a Split, and jumps past the rest when it matches
+                let mut jumps = Vec::new();
+                let (last, rest) = alternation.asts.split_last().unwrap();
+                for ast in rest {
+                    let split = self.push(Inst::Split(0, 0));
+                    self.compile(ast)?;
+                    jumps.push(self.push(Inst::Jump(0)));
+                    self.program[split] = Inst::Split(split + 1, self.program.len());
+                }
+                self.compile(last)?;
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            },
+            Ast::Concat(concat) => for ast in &concat.asts {
+                self.compile(ast)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn split(&self, body: usize, skip: usize, greedy: bool) -> Inst {
+        if greedy { Inst::Split(body, skip) } else { Inst::Split(skip, body) }
+    }
+
+    // the ast is compiled once for each required or optional repetition
+    fn repeat(&mut self, ast: &Ast, min: u32, max: Option<u32>, greedy: bool) -> Result<(), String> {
+        // as the regex crate does
+        if max.is_some_and(|max| max < min) {
+            return Err("invalid repetition range".to_string())
+        }
+        for _ in 0..min {
+            self.compile(ast)?;
+        }
+        match max {
+            // optional repetitions, each skipping to the end if it doesn't match
+            Some(max) => {
+                let mut splits = Vec::new();
+                for _ in min..max {
+                    splits.push(self.push(Inst::Split(0, 0)));
+                    self.compile(ast)?;
+                }
+                let end = self.program.len();
+                for split in splits {
+                    self.program[split] = self.split(split + 1, end, greedy);
+                }
+            },
+            // a loop, which stops if an iteration matches the empty string
+            None => {
+                let loop_index = self.loops;
+                self.loops += 1;
+                let split = self.push(Inst::Split(0, 0));
+                self.push(Inst::Mark(loop_index));
+                self.compile(ast)?;
+                self.push(Inst::Progress(loop_index));
+                self.push(Inst::Jump(split));
+                self.program[split] = self.split(split + 1, self.program.len(), greedy);
+            },
+        }
+        Ok(())
+    }
+}
+
+fn class_ranges(item: &ClassSetItem, ranges: &mut Vec<(char, char)>) -> Result<(), String> {
+    match item {
+        ClassSetItem::Empty(_) => (),
+        ClassSetItem::Literal(literal) => ranges.push((literal.c, literal.c)),
+        ClassSetItem::Range(range) => ranges.push((range.start.c, range.end.c)),
+        ClassSetItem::Ascii(ascii) if !ascii.negated => ranges.extend_from_slice(ascii_ranges(&ascii.kind)),
+        ClassSetItem::Union(union) => for item in &union.items {
+            class_ranges(item, ranges)?;
+        },
+        _ => return Err(unsupported("a nested or negated class")),
+    }
+    Ok(())
+}
+
+// where the thread of execution goes after a failure
+enum Backtrack {
+    Resume(usize, usize), // program counter and position
+    Restore(usize, Option<usize>), // slot and its previous value
+}
+
+#[derive(Clone, Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+    groups: usize, // the highest group number
+    names: HashMap<String, usize>,
+    loops: usize,
+    case_insensitive: bool,
+    multi_line: bool,
+    longest: bool,
+    separator: char, // the line terminator
+}
+
+impl Regex {
+    pub fn new(ast: &Ast, case_insensitive: bool, multi_line: bool, longest: bool, separator: char) -> Result<Regex, String> {
+        let mut compiler = Compiler { program: Vec::new(), groups: 0, names: HashMap::new(), loops: 0 };
+        compiler.push(Inst::Save(0));
+        compiler.compile(ast)?;
+        compiler.push(Inst::Save(1));
+        compiler.push(Inst::Match);
+        Ok(Regex {
+            program: compiler.program,
+            groups: compiler.groups,
+            names: compiler.names,
+            loops: compiler.loops,
+            case_insensitive,
+            multi_line,
+            longest,
+            separator,
+        })
+    }
+
+    // any match will do, so the longest needn't be found
+    pub fn is_match(&self, text: &str) -> bool {
+        self.captures_at(text, 0, false).is_some()
+    }
+
+    pub fn captures_iter<'r, 't>(&'r self, text: &'t str) -> CaptureMatches<'r, 't> {
+        CaptureMatches { regex: self, text, last_end: 0, last_match: None }
+    }
+
+    // the slots of the leftmost match which starts at or after start
+    fn captures_at(&self, text: &str, start: usize, longest: bool) -> Option<Vec<Option<usize>>> {
+        (start..=text.len()).filter(|i| text.is_char_boundary(*i)).find_map(|i| self.run(text, i, longest))
+    }
+
+    fn same_char(&self, a: char, b: char) -> bool {
+        a == b || self.case_insensitive && a.to_lowercase().eq(b.to_lowercase())
+    }
+
+    fn class_matches(&self, class: &CharClass, c: char) -> bool {
+        let found = class.contains(c) ||
+            self.case_insensitive && c.to_lowercase().chain(c.to_uppercase()).any(|d| class.contains(d));
+        found != class.negated
+    }
+
+    fn assertion_holds(&self, kind: &AssertionKind, text: &str, pos: usize) -> bool {
+        match kind {
+            AssertionKind::StartText => pos == 0,
+            AssertionKind::EndText => pos == text.len(),
+            AssertionKind::StartLine => pos == 0 || self.multi_line && text[..pos].ends_with(self.separator),
+            AssertionKind::EndLine => pos == text.len() || self.multi_line && text[pos..].starts_with(self.separator),
+            _ => false,
+        }
+    }
+
+    // the length of the text at the start of rest which matches the text of a group
+    fn backref_len(&self, group: &str, rest: &str) -> Option<usize> {
+        let mut len = 0;
+        let mut chars = rest.chars();
+        for a in group.chars() {
+            let b = chars.next()?;
+            if !self.same_char(a, b) {
+                return None
+            }
+            len += b.len_utf8();
+        }
+        Some(len)
+    }
+
+    // the slots of a match which starts at start, if there is one
+    // group n is in slots 2n and 2n+1, followed by the start of the current iteration of each loop
+    fn run(&self, text: &str, start: usize, longest: bool) -> Option<Vec<Option<usize>>> {
+        let loop_base = 2 * (self.groups + 1);
+        let mut slots = vec![None; loop_base + self.loops];
+        let mut best: Option<Vec<Option<usize>>> = None;
+        let mut stack = vec![Backtrack::Resume(0, start)];
+        while let Some(backtrack) = stack.pop() {
+            let (mut pc, mut pos) = match backtrack {
+                Backtrack::Resume(pc, pos) => (pc, pos),
+                Backtrack::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue
+                },
+            };
+            loop {
+                let next = text[pos..].chars().next();
+                match &self.program[pc] {
+                    Inst::Match if !longest => return Some(slots),
+                    // keep looking for a longer match, unless none is possible
+                    Inst::Match => {
+                        if best.as_ref().is_none_or(|l| l[1] < Some(pos)) {
+                            best = Some(slots.clone());
+                        }
+                        if pos == text.len() {
+                            return best
+                        }
+                        break
+                    },
+                    Inst::Char(c) => match next {
+                        Some(d) if self.same_char(*c, d) => pos += d.len_utf8(),
+                        _ => break,
+                    },
+                    Inst::Any => match next {
+                        Some(d) if d != self.separator => pos += d.len_utf8(),
+                        _ => break,
+                    },
+                    Inst::Class(class) => match next {
+                        Some(d) if self.class_matches(class, d) => pos += d.len_utf8(),
+                        _ => break,
+                    },
+                    Inst::Assert(kind) => if !self.assertion_holds(kind, text, pos) {
+                        break
+                    },
+                    Inst::Save(slot) => {
+                        stack.push(Backtrack::Restore(*slot, slots[*slot]));
+                        slots[*slot] = Some(pos);
+                    },
+                    Inst::Mark(loop_index) => {
+                        let slot = loop_base + loop_index;
+                        stack.push(Backtrack::Restore(slot, slots[slot]));
+                        slots[slot] = Some(pos);
+                    },
+                    Inst::Progress(loop_index) => if slots[loop_base + loop_index] == Some(pos) {
+                        break
+                    },
+                    Inst::Split(first, second) => {
+                        stack.push(Backtrack::Resume(*second, pos));
+                        pc = *first;
+                        continue
+                    },
+                    Inst::Jump(target) => {
+                        pc = *target;
+                        continue
+                    },
+                    // a group which did not participate in the match matches nothing
+                    Inst::Backref(n) => match (slots.get(2 * n).copied().flatten(), slots.get(2 * n + 1).copied().flatten()) {
+                        (Some(s), Some(e)) => match self.backref_len(&text[s..e], &text[pos..]) {
+                            Some(len) => pos += len,
+                            None => break,
+                        },
+                        _ => break,
+                    },
+                }
+                pc += 1;
+            }
+        }
+        best
+    }
+}
+
+// the groups of one match
+#[derive(Clone, Debug)]
+pub struct Captures<'r, 't> {
+    text: &'t str,
+    slots: Vec<Option<usize>>,
+    names: &'r HashMap<String, usize>,
+}
+
+impl Captures<'_, '_> {
+    // the start and end of the whole match
+    pub fn range(&self) -> (usize, usize) {
+        (self.slots[0].unwrap(), self.slots[1].unwrap())
+    }
+}
+
+impl Groups for Captures<'_, '_> {
+    fn group(&self, i: usize) -> Option<&str> {
+        match (self.slots.get(2 * i)?, self.slots.get(2 * i + 1)?) {
+            (Some(start), Some(end)) => Some(&self.text[*start..*end]),
+            _ => None,
+        }
+    }
+
+    fn name(&self, name: &str) -> Option<&str> {
+        self.group(*self.names.get(name)?)
+    }
+}
+
+// successive non-overlapping matches, as the regex crate finds them
+pub struct CaptureMatches<'r, 't> {
+    regex: &'r Regex,
+    text: &'t str,
+    last_end: usize, // where to search next
+    last_match: Option<usize>, // the end of the previous match
+}
+
+impl<'r, 't> Iterator for CaptureMatches<'r, 't> {
+    type Item = Captures<'r, 't>;
+
+    fn next(&mut self) -> Option<Captures<'r, 't>> {
+        loop {
+            if self.last_end > self.text.len() {
+                return None
+            }
+            let slots = self.regex.captures_at(self.text, self.last_end, self.regex.longest)?;
+            let captures = Captures { text: self.text, slots, names: &self.regex.names };
+            let (start, end) = captures.range();
+            if start == end {
+                // an empty match is not allowed right after the previous match
+                self.last_end = end + self.text[end..].chars().next().map_or(1, char::len_utf8);
+                if self.last_match == Some(end) {
+                    continue
+                }
+            } else {
+                self.last_end = end;
+            }
+            self.last_match = Some(end);
+            return Some(captures)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::regex::parser::{Syntax, new_regex_input, parse};
+    use assert_ok::assert_ok;
+
+    fn compiled(syntax: Syntax, pattern: &str, case_insensitive: bool, multi_line: bool, longest: bool) -> Regex {
+        let mut s = new_regex_input(pattern);
+        s.extra.syntax = syntax;
+        let (rest, ast) = assert_ok!(parse('/', s));
+        assert_eq!(rest.fragment(), &"", "incomplete parse of {}", pattern);
+        assert_ok!(Regex::new(&ast, case_insensitive, multi_line, longest, '\n'))
+    }
+
+    fn basic(pattern: &str) -> Regex {
+        compiled(Syntax::Basic, pattern, false, false, false)
+    }
+
+    #[test]
+    fn repeated_group() {
+        assert!(basic(r"^\(a\)\1$").is_match("aa"));
+        assert!(!basic(r"^\(a\)\1$").is_match("ab"));
+        assert!(basic(r"\(ab\)\{2\}\1").is_match("xababab"));
+        assert!(!basic(r"\(ab\)\{2\}\1").is_match("abab"));
+    }
+
+    #[test]
+    fn nested_groups() {
+        assert!(basic(r"^\(\(a\)b\)\2$").is_match("aba"));
+        assert!(basic(r"^\(\(a\)b\)\1$").is_match("abab"));
+    }
+
+    #[test]
+    fn extended_alternation() {
+        let regex = compiled(Syntax::Extended, r"^(a|b)\1$", false, false, false);
+        assert!(regex.is_match("bb"));
+        assert!(!regex.is_match("ab"));
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let regex = compiled(Syntax::Basic, r"^\(.*\)\n\1$", false, true, false);
+        assert!(regex.is_match("x\nabc\nabc\ny"));
+        assert!(!regex.is_match("abc\nabd"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert!(compiled(Syntax::Basic, r"\([a-z]\)\1", true, false, false).is_match("aA"));
+        assert!(!basic(r"\([a-z]\)\1").is_match("aA"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(basic(r"^\([[:digit:]]\)[^[:alpha:]]\1$").is_match("1-1"));
+        assert!(!basic(r"^\([[:digit:]]\)[^[:alpha:]]\1$").is_match("1a1"));
+    }
+
+    #[test]
+    fn empty_loop_terminates() {
+        assert!(basic(r"\(\(a*\)*\)\1b").is_match("b"));
+    }
+
+    #[test]
+    fn unset_group() {
+        let regex = compiled(Syntax::Extended, r"(a)|b\1", false, false, false);
+        assert!(regex.is_match("a"));
+        assert!(!regex.is_match("b"));
+    }
+
+    #[test]
+    fn captures() {
+        let regex = basic(r"\(a*\)\(b\)\2");
+        let captures: Vec<(usize, usize)> = regex.captures_iter("abbcaabb").map(|c| c.range()).collect();
+        assert_eq!(captures, [(0, 3), (4, 8)]);
+        let first = regex.captures_iter("abbcaabb").next().unwrap();
+        assert_eq!(first.group(1), Some("a"));
+        assert_eq!(first.group(3), None);
+    }
+
+    fn longest_ranges(pattern: &str, text: &str) -> Vec<(usize, usize)> {
+        compiled(Syntax::Extended, pattern, false, false, true).captures_iter(text).map(|c| c.range()).collect()
+    }
+
+    #[test]
+    fn longest_alternative() {
+        assert_eq!(longest_ranges("a|ab", "abxab"), [(0, 2), (3, 5)]);
+        assert_eq!(longest_ranges("(a|ab)(c|bcd)", "abcd"), [(0, 4)]);
+        assert_eq!(longest_ranges("x*|y+", "yyz"), [(0, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn longest_groups_from_first_way_found() {
+        let regex = compiled(Syntax::Extended, "(a|ab)(bc|c)", false, false, true);
+        let captures = regex.captures_iter("abc").next().unwrap();
+        assert_eq!(captures.group(1), Some("a"));
+        assert_eq!(captures.group(2), Some("bc"));
+    }
+
+    #[test]
+    fn named_groups() {
+        let regex = compiled(Syntax::Teal, "(?P<x>a)(b)(?P<y>c)?", false, false, true);
+        let captures = regex.captures_iter("ab").next().unwrap();
+        assert_eq!(captures.name("x"), Some("a"));
+        assert_eq!(captures.name("y"), None);
+        assert_eq!(captures.name("z"), None);
+    }
+}
@@ -70,7 +70,7 @@ impl Equivalent for ClassSetItem {
             (Empty(_), Empty(_)) => true,
             (Literal(a), Literal(b)) => a.equivalent(b),
             (Range(a), Range(b)) => a.start.equivalent(&b.start) && a.end.equivalent(&b.end),
-            (Ascii(_), Ascii(_)) => panic!("not implemented"),
+            (Ascii(a), Ascii(b)) => a.kind == b.kind && a.negated == b.negated,
             (Unicode(_), Unicode(_)) => panic!("not implemented"),
             (Perl(_), Perl(_)) => panic!("not implemented"),
             (Bracketed(_), Bracketed(_)) => panic!("not implemented"),
@@ -120,6 +120,6 @@ impl Equivalent for Alternation {
 
 impl Equivalent for Concat {
     fn equivalent(&self, other: &Concat) -> bool {
-        self.asts.len() == other.asts.len() && std::iter::zip(&self.asts, &other.asts).all(|(a, b)| a.equivalent(&b))
+        self.asts.len() == other.asts.len() && std::iter::zip(&self.asts, &other.asts).all(|(a, b)| a.equivalent(b))
     }
 }
@@ -0,0 +1,153 @@
+use crate::regex::Groups;
+
+use ::regex::RegexBuilder;
+use regex_automata::{Anchored, Input, MatchKind, meta};
+use regex_automata::util::{captures, syntax};
+
+// A leftmost-longest matcher for patterns without backreferences, built from two automata:
+// the regex crate's leftmost-first one finds where the leftmost match starts, since any match found first starts there,
+// then an anchored search which visits all the matches, rather than stopping at the first alternative,
+// finds the longest match starting there.
+// As with the backtracking matcher, the groups are those of the first way found to match it,
+// rather than the POSIX rules for subexpressions.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    first: ::regex::Regex,
+    all: meta::Regex,
+}
+
+impl Regex {
+    pub fn new(pattern: &str, case_insensitive: bool, multi_line: bool, separator: u8) -> Result<Regex, String> {
+        let first = RegexBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .multi_line(multi_line)
+            .line_terminator(separator)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let all = meta::Regex::builder()
+            .configure(meta::Regex::config().match_kind(MatchKind::All).line_terminator(separator))
+            .syntax(syntax::Config::new()
+                .case_insensitive(case_insensitive)
+                .multi_line(multi_line)
+                .line_terminator(separator))
+            .build(pattern)
+            .map_err(|e| e.to_string())?;
+        Ok(Regex { first, all })
+    }
+
+    // any match will do, so the longest needn't be found
+    pub fn is_match(&self, text: &str) -> bool {
+        self.first.is_match(text)
+    }
+
+    pub fn captures_iter<'r, 't>(&'r self, text: &'t str) -> CaptureMatches<'r, 't> {
+        CaptureMatches { regex: self, text, last_end: 0, last_match: None }
+    }
+
+    // the longest match starting where the leftmost match at or after start does
+    fn captures_at<'t>(&self, text: &'t str, start: usize) -> Option<Captures<'t>> {
+        let leftmost = self.first.find_at(text, start)?.start();
+        let mut captures = self.all.create_captures();
+        self.all.search_captures(&Input::new(text).range(leftmost..).anchored(Anchored::Yes), &mut captures);
+        Some(Captures { text, captures })
+    }
+}
+
+// the groups of one match
+#[derive(Clone, Debug)]
+pub struct Captures<'t> {
+    text: &'t str,
+    captures: captures::Captures,
+}
+
+impl Captures<'_> {
+    // the start and end of the whole match
+    pub fn range(&self) -> (usize, usize) {
+        let m = self.captures.get_match().unwrap();
+        (m.start(), m.end())
+    }
+}
+
+impl Groups for Captures<'_> {
+    fn group(&self, i: usize) -> Option<&str> {
+        self.captures.get_group(i).map(|span| &self.text[span.range()])
+    }
+
+    fn name(&self, name: &str) -> Option<&str> {
+        self.captures.get_group_by_name(name).map(|span| &self.text[span.range()])
+    }
+}
+
+// successive non-overlapping matches, as the regex crate finds them
+pub struct CaptureMatches<'r, 't> {
+    regex: &'r Regex,
+    text: &'t str,
+    last_end: usize, // where to search next
+    last_match: Option<usize>, // the end of the previous match
+}
+
+impl<'t> Iterator for CaptureMatches<'_, 't> {
+    type Item = Captures<'t>;
+
+    fn next(&mut self) -> Option<Captures<'t>> {
+        loop {
+            if self.last_end > self.text.len() {
+                return None
+            }
+            let captures = self.regex.captures_at(self.text, self.last_end)?;
+            let (start, end) = captures.range();
+            if start == end {
+                // an empty match is not allowed right after the previous match
+                self.last_end = end + self.text[end..].chars().next().map_or(1, char::len_utf8);
+                if self.last_match == Some(end) {
+                    continue
+                }
+            } else {
+                self.last_end = end;
+            }
+            self.last_match = Some(end);
+            return Some(captures)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assert_ok::assert_ok;
+
+    fn longest_ranges(pattern: &str, text: &str) -> Vec<(usize, usize)> {
+        let regex = assert_ok!(Regex::new(pattern, false, false, b'\n'));
+        regex.captures_iter(text).map(|c| c.range()).collect()
+    }
+
+    #[test]
+    fn longest_alternative() {
+        assert_eq!(longest_ranges("a|ab", "abxab"), [(0, 2), (3, 5)]);
+        assert_eq!(longest_ranges("(a|ab)(c|bcd)", "abcd"), [(0, 4)]);
+        assert_eq!(longest_ranges("x*|y+", "yyz"), [(0, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn longest_groups_from_first_way_found() {
+        let regex = assert_ok!(Regex::new("(a|ab)(bc|c)", false, false, b'\n'));
+        let captures = regex.captures_iter("abc").next().unwrap();
+        assert_eq!(captures.group(1), Some("a"));
+        assert_eq!(captures.group(2), Some("bc"));
+    }
+
+    #[test]
+    fn named_groups() {
+        let regex = assert_ok!(Regex::new("(?P<x>a)(b)(?P<y>c)?", false, false, b'\n'));
+        let captures = regex.captures_iter("ab").next().unwrap();
+        assert_eq!(captures.name("x"), Some("a"));
+        assert_eq!(captures.name("y"), None);
+        assert_eq!(captures.name("z"), None);
+    }
+
+    #[test]
+    fn long_line() {
+        let text = "a".repeat(500_000);
+        assert_eq!(longest_ranges("(a|a)*b|(a|a)*", &text), [(0, 500_000)]);
+    }
+}
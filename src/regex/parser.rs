@@ -4,11 +4,12 @@ use nom::branch::alt;
 use nom::error::{ Error, ErrorKind};
 use nom::{
     multi::{many0, many1},
-    combinator::{not, opt, peek},
-    Err, Finish, IResult,
+    combinator::{fail, not, opt, peek},
+    sequence::preceded,
+    Err, IResult,
 };
 use nom_locate::{LocatedSpan};
-use regex_syntax::ast::{Alternation, Assertion, AssertionKind, Ast, CaptureName, Class, ClassBracketed, ClassSet, ClassSetItem, ClassSetRange, ClassSetUnion, Concat, Flags, Group, GroupKind, Literal, LiteralKind, Position, Repetition, RepetitionKind, RepetitionOp, RepetitionRange, Span};
+use regex_syntax::ast::{Alternation, Assertion, AssertionKind, Ast, CaptureName, Class, ClassAscii, ClassAsciiKind, ClassBracketed, ClassSet, ClassSetItem, ClassSetRange, ClassSetUnion, Concat, Flags, Group, GroupKind, Literal, LiteralKind, Position, Repetition, RepetitionKind, RepetitionOp, RepetitionRange, Span};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Syntax {
@@ -20,10 +21,14 @@ pub enum Syntax {
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct ExtraState {
     pub last_regex: u32,
+    pub group_depth: u32, // number of enclosing groups
     // below are not state; they are set once at start of parsing
     pub end_char: char,
     pub syntax: Syntax,
     pub gnu: bool,
+    pub posix: bool, // without the GNU escapes POSIX leaves undefined
+    pub longest: bool, // POSIX leftmost-longest matching rather than leftmost-first
+    pub separator: u8, // the line terminator for ^ and $ with the M flag
 }
 
 pub type Input<'a> = LocatedSpan<&'a str, ExtraState>;
@@ -33,9 +38,13 @@ pub type Progress<'a, T = Ast> = IResult<Input<'a>, T>;
 pub fn new_regex_input<'a>(s: &'a str) -> Input<'a> {
     LocatedSpan::new_extra(s, ExtraState {
         last_regex: 0,
+        group_depth: 0,
         end_char: '/',
         syntax: Syntax::Teal,
-        gnu: false
+        gnu: false,
+        posix: false,
+        longest: false,
+        separator: b'\n',
     })
 }
 
@@ -51,33 +60,60 @@ fn position(s: Input) -> Position {
 // only valid in ()?
 fn empty(s: Input) -> Progress {
     let pos = position(s);
-    Ok((s, Ast::Empty(Span{start: pos, end: pos.clone()})))
+    Ok((s, Ast::Empty(Span{start: pos, end: pos})))
 }
 
 fn dot(s: Input) -> Progress {
     let start = position(s);
     let (s, _) = char('.')(s)?;
     let end = position(s);
-    Ok((s, Ast::Dot(Span{start: start, end: end})))
+    Ok((s, Ast::Dot(Span{start, end})))
 }
 
 // re_format says these have special meaning if not escaped with \, { is handled extra-specially
 const SPECIAL_CHARS : &str = "^.[$()|*+?\\";
+// POSIX: in ERE, ) is special only when it closes a group
+const UNGROUPED_SPECIAL_CHARS : &str = "^.[$(|*+?\\";
+// in BRE, ( ) { } | + ? are literals unless escaped
+// ^ and $ are literals except where assertion or branch takes them as anchors
+const BASIC_SPECIAL_CHARS : &str = ".[*\\";
+
+// ( ) { } in BRE are operators when escaped, as are | + ? with GNU extensions
+fn is_basic_operator(c: char, gnu: bool) -> bool {
+    "(){}".contains(c) || gnu && "|+?".contains(c)
+}
+
+// the operator c, written \c in BRE
+// the delimiter is never an operator: escaped it is literal, and unescaped it ends the regex
+fn operator<'a>(c: char) -> impl FnMut(Input<'a>) -> Progress<'a, char> {
+    move |s: Input<'a>| match s.extra.syntax {
+        _ if c == s.extra.end_char => fail(s),
+        Syntax::Basic if !is_basic_operator(c, s.extra.gnu) => fail(s),
+        Syntax::Basic => preceded(char('\\'), char(c))(s),
+        _ => char(c)(s),
+    }
+}
 
 fn literal(s: Input) -> Progress {
     let start = position(s);
-    let (s, lit) = none_of(SPECIAL_CHARS)(s)?;
+    let special = match s.extra.syntax {
+        Syntax::Basic => BASIC_SPECIAL_CHARS,
+        Syntax::Extended if s.extra.group_depth == 0 => UNGROUPED_SPECIAL_CHARS,
+        _ => SPECIAL_CHARS,
+    };
+    let (s, lit) = none_of(special)(s)?;
     if lit == s.extra.end_char {
         return Err(Err::Error(Error::new(s, ErrorKind::Fail)))
     }
-    if lit == '{' { // taken as a literal if it cannot be a bound
+    if lit == '{' && s.extra.syntax != Syntax::Basic { // taken as a literal if it cannot be a bound
         // TODO support this in Teal syntax?
         peek(not(one_of("0123456789")))(s)?;
     }
     let end = position(s);
     Ok((s, Ast::Literal(Literal{
-        span: Span{start: start, end: end},
-        kind: LiteralKind::Verbatim,
+        span: Span{start, end},
+        // a BRE literal may be special to the regex crate
+        kind: if "\\.+*?()|[]{}^$".contains(lit) { LiteralKind::Punctuation } else { LiteralKind::Verbatim },
         c: lit
     })))
 }
@@ -89,20 +125,73 @@ fn escaped_literal(s: Input) -> Progress {
     let start = position(s);
     let (s, _) = char('\\')(s)?;
     let (s, c) = anychar(s)?;
+    let delimiter = c == s.extra.end_char;
+    if s.extra.syntax == Syntax::Basic && is_basic_operator(c, s.extra.gnu) && !delimiter {
+        return fail(s)
+    }
+    // \1 to \9 are backreferences, except in Teal syntax
+    if s.extra.syntax != Syntax::Teal && ('1'..='9').contains(&c) && !delimiter {
+        return fail(s)
+    }
     let end = position(s);
+    let (kind, c) = match c {
+        _ if delimiter && !regex_syntax::is_meta_character(c) => (Verbatim, c),
+        '^' | '.' | '[' | '$' | '(' | ')' | '|' | '*' | '+' | '?' | '{' | '\\' => (Punctuation, c),
+        'n' => (Special(LineFeed), '\n'),
+        'r' => (Special(CarriageReturn), '\r'),
+        't' => (Special(Tab), '\t'),
+        _ => (Verbatim, c),
+    };
     Ok((s, Ast::Literal(Literal{
-        span: Span{start: start, end: end},
-        kind: match c {
-            '^' | '.' | '[' | '$' | '(' | ')' | '|' | '*' | '+' | '?' | '{' | '\\' => Punctuation,
-            'n' => Special(LineFeed),
-            'r' => Special(CarriageReturn),
-            't' => Special(Tab),
-            _ => Verbatim,
-        },
-        c: c
+        span: Span{start, end},
+        kind,
+        c
+    })))
+}
+
+// regex_syntax has no backreferences, so \n is represented as an empty group named n, with index 0
+// the regex crate rejects that name, so only regex::backtrack can match it
+fn backreference(s: Input) -> Progress {
+    if s.extra.syntax == Syntax::Teal {
+        return fail(s)
+    }
+    let start = position(s);
+    let (s, _) = char('\\')(s)?;
+    let (s, n) = one_of("123456789")(s)?;
+    if n == s.extra.end_char {
+        return fail(s)
+    }
+    // POSIX: a backreference must follow the group it refers to
+    if n.to_digit(10).unwrap() > s.extra.last_regex {
+        return fail(s)
+    }
+    let end = position(s);
+    let span = Span { start, end };
+    Ok((s, Ast::Group(Group {
+        span,
+        kind: GroupKind::CaptureName(CaptureName { span, name: n.to_string(), index: 0 }),
+        ast: Box::new(Ast::Empty(span)),
     })))
 }
 
+// the group number a backreference refers to
+pub fn backreference_number(group: &Group) -> Option<usize> {
+    match &group.kind {
+        GroupKind::CaptureName(name) if name.index == 0 => name.name.parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn has_backreference(ast: &Ast) -> bool {
+    match ast {
+        Ast::Group(group) => backreference_number(group).is_some() || has_backreference(&group.ast),
+        Ast::Repetition(repetition) => has_backreference(&repetition.ast),
+        Ast::Alternation(alternation) => alternation.asts.iter().any(has_backreference),
+        Ast::Concat(concat) => concat.asts.iter().any(has_backreference),
+        _ => false,
+    }
+}
+
 fn named_group_intro(s: Input) -> Progress<GroupKind> {
     let start = position(s);
     let (s, _) = char('P')(s)?;
@@ -127,34 +216,52 @@ fn non_capture_group_intro(s: Input) -> Progress<GroupKind> {
 }
 
 fn group(s: Input) -> Progress {
-    use nom::sequence::preceded;
     let start = position(s);
-    let (s, _) = char( '(' )(s)?;
-    let (s, group_kind) = match s.extra.syntax {
+    let (mut s, _) = operator('(')(s)?;
+    s.extra.group_depth += 1;
+    let (mut s, group_kind) = match s.extra.syntax {
         Syntax::Basic => (s, None),
         Syntax::Extended => opt(preceded(char('?'), non_capture_group_intro))(s)?,
         Syntax::Teal => opt(preceded(char('?'), alt((named_group_intro, non_capture_group_intro))))(s)?
     };
+    // groups are numbered in the order they open, as backreferences expect
+    let kind = match group_kind {
+        Some(k) => k,
+        None => {
+            s.extra.last_regex += 1;
+            GroupKind::CaptureIndex(s.extra.last_regex)
+        }
+    };
     let (s, ast) = alt((alternation, empty))(s)?;
-    let (mut s, _) = char( ')' )(s)?;
+    let (mut s, _) = operator(')')(s)?;
+    s.extra.group_depth -= 1;
     let end = position(s);
 
     Ok((s, Ast::Group( Group {
-        span: Span{ start: start, end: end},
-        kind: match group_kind {
-            Some(k) => k,
-            None => {
-                s.extra.last_regex += 1;
-                GroupKind::CaptureIndex(s.extra.last_regex)
-            }
-        },
+        span: Span{ start, end},
+        kind,
         ast: Box::new(ast),
     })))
 }
 
+// in BRE, ^ is only an anchor at the start of a branch, so it is parsed by branch_start
+// and $ is only an anchor at the end of the pattern, subexpression, or GNU alternative
 fn assertion(s: Input) -> Progress {
     let start = position(s);
-    let (s, c) = one_of("^$")(s)?;
+    let (s, c) = match s.extra.syntax {
+        Syntax::Basic => {
+            let (s, c) = char('$')(s)?;
+            let end_char = s.extra.end_char;
+            peek(alt((
+                nom::combinator::eof,
+                nom::combinator::recognize(char(end_char)),
+                nom::combinator::recognize(operator(')')),
+                nom::combinator::recognize(operator('|')),
+            )))(s)?;
+            (s, c)
+        },
+        _ => one_of("^$")(s)?,
+    };
     let kind = match c {
         '^' => AssertionKind::StartLine,
         '$' => AssertionKind::EndLine,
@@ -163,7 +270,7 @@ fn assertion(s: Input) -> Progress {
     let end = position(s);
     Ok((s, Ast::Assertion(Assertion {
         span: Span { start, end },
-        kind: kind
+        kind
     })))
 }
 
@@ -172,9 +279,12 @@ fn class(s: Input) -> Progress {
     let start = position(s);
     let (s, _) = char('[')(s)?;
     let (s, negated) = opt(char('^'))(s)?;
-    let (s, items) = many1(alt((class_range, class_literal)))(s)?;
+    // POSIX: ] is literal as the first item
+    let (s, first) = class_item(true)(s)?;
+    let (s, mut items) = many0(class_item(false))(s)?;
     let (s, _) = char(']')(s)?;
     let end = position(s);
+    items.insert(0, first);
     Ok((s, Ast::Class(Class::Bracketed( ClassBracketed {
         span: Span { start, end },
         negated: negated.is_some(),
@@ -192,44 +302,112 @@ fn class(s: Input) -> Progress {
     }) )))
 }
 
-fn class_literal(s: Input) -> Progress<ClassSetItem> {
+fn class_item<'a>(first: bool) -> impl FnMut(Input<'a>) -> Progress<'a, ClassSetItem> {
+    move |s: Input<'a>| alt((class_ascii, class_range(first), class_literal(first)))(s)
+}
+
+// [:alpha:] and the other named classes
+fn class_ascii(s: Input) -> Progress<ClassSetItem> {
     let start = position(s);
-    let (s, c) = none_of("]")(s)?;
+    let (s, name) = nom::sequence::delimited(
+        nom::bytes::complete::tag("[:"), nom::character::complete::alpha1, nom::bytes::complete::tag(":]"))(s)?;
+    let kind = match ClassAsciiKind::from_name(name.fragment()) {
+        Some(kind) => kind,
+        None => return fail(s),
+    };
     let end = position(s);
-    Ok((s, ClassSetItem::Literal(Literal {
+    Ok((s, ClassSetItem::Ascii(ClassAscii {
         span: Span { start, end },
-        kind: LiteralKind::Verbatim,
-        c: c
+        kind,
+        negated: false
     })))
 }
 
-fn class_range(s: Input) -> Progress<ClassSetItem> {
-    let start = position(s);
-    let (s, a) = none_of("-]")(s)?;
-    let (s, _) = char('-')(s)?;
-    let (s, z) = none_of("-]")(s)?;
-    let end = position(s);
-    let span = Span { start, end };  // I'm lazy, don't care where each char is
-    Ok((s, ClassSetItem::Range(ClassSetRange {
-        span: Span { start, end },
-        start: Literal {
-            span, kind: LiteralKind::Verbatim,
-            c: a
-        },
-        end: Literal {
-            span, kind: LiteralKind::Verbatim,
-            c: z
+// a collating symbol [.c.] or equivalence class [=c=]
+// only single characters are supported, so both are the character itself
+fn collating_element(s: Input) -> Progress<char> {
+    let (s, _) = char('[')(s)?;
+    let (s, delimiter) = one_of(".=")(s)?;
+    let (s, c) = anychar(s)?;
+    let (s, _) = char(delimiter)(s)?;
+    let (s, _) = char(']')(s)?;
+    Ok((s, c))
+}
+
+// GNU: \n, \r, \t, and \\ are escapes in a bracket expression too, where POSIX makes \ literal
+fn class_escape(s: Input) -> Progress<char> {
+    if s.extra.posix || s.extra.syntax == Syntax::Teal {
+        return fail(s)
+    }
+    let (s, _) = char('\\')(s)?;
+    let (s, c) = one_of("nrt\\")(s)?;
+    Ok((s, match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        _ => c,
+    }))
+}
+
+// one character of a bracket expression
+// [ is only literal if it can't start a named class, collating symbol, or equivalence class
+fn class_char<'a>(first: bool) -> impl FnMut(Input<'a>) -> Progress<'a, char> {
+    move |s: Input<'a>| {
+        if let Ok(progress) = alt((collating_element, class_escape))(s) {
+            return Ok(progress)
         }
-    })))
+        let (rest, c) = anychar(s)?;
+        if (c == ']' && !first) || (c == '[' && peek(one_of::<_, _, Error<Input>>(":.="))(rest).is_ok()) {
+            return fail(s)
+        }
+        Ok((rest, c))
+    }
+}
+
+fn class_set_literal(span: Span, syntax: Syntax, c: char) -> Literal {
+    // POSIX: \ is literal in a bracket expression, but Teal leaves escapes to the regex crate
+    let escaped = regex_syntax::is_meta_character(c) && !(c == '\\' && syntax == Syntax::Teal);
+    Literal {
+        span,
+        kind: if escaped { LiteralKind::Punctuation } else { LiteralKind::Verbatim },
+        c
+    }
+}
+
+// POSIX: - is literal as the first or last item
+fn class_literal<'a>(first: bool) -> impl FnMut(Input<'a>) -> Progress<'a, ClassSetItem> {
+    move |s: Input<'a>| {
+        let start = position(s);
+        let (s, c) = class_char(first)(s)?;
+        let end = position(s);
+        Ok((s, ClassSetItem::Literal(class_set_literal(Span { start, end }, s.extra.syntax, c))))
+    }
+}
+
+// POSIX: - may also be the end of a range
+fn class_range<'a>(first: bool) -> impl FnMut(Input<'a>) -> Progress<'a, ClassSetItem> {
+    move |s: Input<'a>| {
+        let start = position(s);
+        let (s, a) = class_char(first)(s)?;
+        let (s, _) = char('-')(s)?;
+        let (s, z) = class_char(false)(s)?;
+        let end = position(s);
+        let span = Span { start, end };  // I'm lazy, don't care where each char is
+        Ok((s, ClassSetItem::Range(ClassSetRange {
+            span: Span { start, end },
+            start: class_set_literal(span, s.extra.syntax, a),
+            end: class_set_literal(span, s.extra.syntax, z),
+        })))
+    }
 }
 
 fn atom(s: Input) -> Progress {
-    alt((group, class, literal, escaped_literal, dot, assertion))(s)
+    alt((group, class, assertion, literal, backreference, escaped_literal, dot))(s)
 }
 
 fn char_quantifier(s: Input) -> Progress<RepetitionOp> {
     let start = position(s);
-    let (s, c) = one_of("*+?")(s)?;
+    let (s, c) = alt((char('*'), operator('+'), operator('?')))(s)?;
     let quantifier = match c {
         '*' => RepetitionKind::ZeroOrMore,
         '+' => RepetitionKind::OneOrMore,
@@ -239,14 +417,14 @@ fn char_quantifier(s: Input) -> Progress<RepetitionOp> {
     };
     let end = position(s);
     Ok((s, RepetitionOp {
-        span: Span{start: start, end: end},
+        span: Span{start, end},
         kind: quantifier
     } ))
 }
 
 fn bound(s: Input) -> Progress<RepetitionOp> {
     let start = position(s);
-    let (s, _) = char('{')(s)?;
+    let (s, _) = operator('{')(s)?;
     let (s, min) = u32(s)?;
     let (s, o_comma) = opt(char(','))(s)?;
     let (s, bound) = match o_comma {
@@ -260,48 +438,89 @@ fn bound(s: Input) -> Progress<RepetitionOp> {
             }
         }
     }?;
-    let (s, _) = char('}')(s)?;
+    let (s, _) = operator('}')(s)?;
     let end = position(s);
     Ok((s, RepetitionOp {
-        span: Span{start: start, end: end},
+        span: Span{start, end},
         kind: bound
     } ))
 }
 
+// an atom, and its quantifier if any
+fn quantified<'a>(mut atom: impl FnMut(Input<'a>) -> Progress<'a>) -> impl FnMut(Input<'a>) -> Progress<'a> {
+    move |s: Input<'a>| {
+        let start = position(s);
+        let (s, atom) = atom(s)?;
+        let (s, o_quantifier) = opt(alt((char_quantifier, bound)))(s)?;
+        let end = position(s);
+        match o_quantifier {
+            None => Ok((s, atom)),
+            Some(quantifier) => {
+                Ok((s, Ast::Repetition(Repetition {
+                    span: Span{start, end},
+                    op: quantifier,
+                    greedy: true,
+                    ast: Box::new(atom)
+                })))
+            }
+        }
+    }
+}
+
 fn quantified_piece(s: Input) -> Progress {
+    quantified(atom)(s)
+}
+
+fn leading_star(s: Input) -> Progress {
     let start = position(s);
-    let (s, atom) = atom(s)?;
-    let (s, o_quantifier) = opt(alt((char_quantifier, bound)))(s)?;
+    let (s, c) = char('*')(s)?;
     let end = position(s);
-    match o_quantifier {
-        None => Ok((s, atom)),
-        Some(quantifier) => {
-            Ok((s, Ast::Repetition(Repetition {
-                span: Span{start: start, end: end},
-                op: quantifier,
-                greedy: true,
-                ast: Box::new(atom)
-            })))
-        }
+    Ok((s, Ast::Literal(Literal {
+        span: Span { start, end },
+        kind: LiteralKind::Punctuation,
+        c
+    })))
+}
+
+// POSIX: in BRE, ^ is an anchor at the start of the pattern,
+// and * is literal at the start of the pattern, after any ^
+// GNU: the same applies at the start of a subexpression or alternative
+fn branch_start(s: Input) -> Progress<Vec<Ast>> {
+    let mut asts = Vec::new();
+    if s.extra.syntax != Syntax::Basic {
+        return Ok((s, asts))
     }
+    let start = position(s);
+    let (s, anchor) = opt(char('^'))(s)?;
+    if anchor.is_some() {
+        asts.push(Ast::Assertion(Assertion {
+            span: Span { start, end: position(s) },
+            kind: AssertionKind::StartLine,
+        }));
+    }
+    let (s, star) = opt(quantified(leading_star))(s)?;
+    asts.extend(star);
+    Ok((s, asts))
 }
 
 fn branch(s: Input) -> Progress {
     let start = position(s);
-    let (s, atoms) = many1(quantified_piece)(s)?;
+    let (s, mut atoms) = branch_start(s)?;
+    let (s, rest) = if atoms.is_empty() { many1(quantified_piece)(s)? } else { many0(quantified_piece)(s)? };
+    atoms.extend(rest);
     let end = position(s);
     if atoms.len() == 1 { // TODO make this less clunky or define a helper
-        Ok((s, atoms.into_iter().nth(0).unwrap()))
+        Ok((s, atoms.into_iter().next().unwrap()))
     } else {
         Ok((s, Ast::Concat(Concat{
-            span: Span{start: start, end: end},
+            span: Span{start, end},
             asts: atoms
         })))
     }
 }
 
 fn bar_branch(s: Input) -> Progress {
-    let (s, _) = char('|')(s)?;
+    let (s, _) = operator('|')(s)?;
     branch(s)
 }
 
@@ -310,7 +529,7 @@ fn alternation(s: Input) -> Progress {
     let (s, first) = branch(s)?;
     let (s, mut rest) = many0(bar_branch)(s)?;
     let end = position(s);
-    if rest.len() == 0 {
+    if rest.is_empty() {
         Ok((s, first))   
     } else {
         rest.insert(0, first);
@@ -325,10 +544,15 @@ pub fn parse(end_char: char, mut s: Input) -> Progress {
     // TODO posix Extended Regular Expressions
     // according to `man re_format` or IEEE 1003.2
     s.extra.end_char = end_char;
+    // groups are numbered from 1 in each regex
+    s.extra.last_regex = 0;
+    s.extra.group_depth = 0;
     alternation(s)
 }
 
-pub fn parse_complete(end_char: char, s: &str) -> Result<Ast, nom::error::Error<Input>> {
+#[cfg(test)]
+pub fn parse_complete(end_char: char, s: &str) -> Result<Ast, nom::error::Error<Input<'_>>> {
+    use nom::Finish;
     let s = new_regex_input(s);
     let (_, ast) = parse(end_char, s).finish()?;
     Ok(ast)
@@ -356,10 +580,33 @@ pub mod tests {
         assert!(regex.is_match(input))
     }
 
+    fn parse_basic(gnu: bool, pattern: &str) -> Ast {
+        let mut s = new_regex_input(pattern);
+        s.extra.syntax = Syntax::Basic;
+        s.extra.gnu = gnu;
+        let (rest, ast) = assert_ok!(parse('/', s));
+        assert_eq!(rest.fragment(), &"", "incomplete parse of {}", pattern);
+        ast
+    }
+
+    // a BRE and the regex crate pattern it should be equivalent to
+    fn basic_as_modern(gnu: bool, pattern: &str, modern: &str) {
+        let expected = Parser::new().parse(modern).unwrap();
+        let actual = parse_basic(gnu, pattern);
+        if !actual.equivalent(&expected) {
+            assert_eq!(actual, expected);
+        }
+    }
+
+    fn basic_matches(gnu: bool, pattern: &str, input: &str) -> bool {
+        let regex = assert_ok!(Regex::new(&format!("{}", parse_basic(gnu, pattern))));
+        regex.is_match(input)
+    }
+
     #[test]
     fn literals() {
         let input = "this is a valid regex";
-        let ast = assert_ok!(parse_complete('/', &input));
+        let ast = assert_ok!(parse_complete('/', input));
         match &ast {
             Ast::Concat(c) => assert_eq!(c.asts.len(), input.len()),
             _ => panic!("unexpected regex parse: {:?}", ast),
@@ -465,4 +712,172 @@ pub mod tests {
         match_modern_syntax("[a-z]")
     }
 
+    #[test]
+    fn basic_group() {
+        basic_as_modern(false, r"\(ab\)*", "(ab)*")
+    }
+
+    #[test]
+    fn basic_bound() {
+        basic_as_modern(false, r"a\{2,3\}", "a{2,3}")
+    }
+
+    #[test]
+    fn basic_literals() {
+        assert!(basic_matches(false, "a(b)", "a(b)"));
+        assert!(!basic_matches(false, "a(b)", "ab"));
+        assert!(basic_matches(false, "a{2}", "a{2}"));
+        assert!(basic_matches(false, "a+?|b", "a+?|b"));
+        assert!(!basic_matches(false, "a+?|b", "b"));
+    }
+
+    #[test]
+    fn basic_escapes_without_gnu() {
+        assert!(basic_matches(false, r"a\+", "a+"));
+        assert!(!basic_matches(false, r"a\+", "aa"));
+        assert!(basic_matches(false, r"a\|b", "a|b"));
+    }
+
+    #[test]
+    fn basic_gnu_operators() {
+        basic_as_modern(true, r"a\+b\?", "a+b?");
+        basic_as_modern(true, r"a\|b", "a|b");
+        basic_as_modern(true, r"\(a\|b\)", "(a|b)");
+    }
+
+    #[test]
+    fn basic_unmatched_paren() {
+        let mut s = new_regex_input(r"a\)");
+        s.extra.syntax = Syntax::Basic;
+        let (rest, _) = assert_ok!(parse('/', s));
+        assert_eq!(rest.fragment(), &r"\)");
+    }
+
+    // pattern, text, and whether the pattern matches the text,
+    // following the context rules of POSIX.1-2017 sections 9.3.3, 9.3.8, and 9.4.3
+    const BASIC_CONTEXT: &[(&str, &str, bool)] = &[
+        ("*a", "*a", true), // * is literal at the start of the RE
+        ("*a", "a", false),
+        ("^*a", "*a", true), // and after an initial ^
+        (r"\(*a\)", "*a", true), // and at the start of a subexpression
+        (r"\(^*a\)", "*a", true),
+        ("**", "***", true), // a literal * may itself be repeated
+        ("a*", "", true), // but elsewhere * is special
+        ("^a", "ba", false), // ^ is an anchor at the start of the RE
+        ("a^b", "a^b", true), // and literal elsewhere
+        ("a^", "a^", true),
+        (r"\(^a\)", "ba", false), // GNU: an anchor at the start of a subexpression
+        ("a$", "ab", false), // $ is an anchor at the end of the RE
+        ("a$b", "a$b", true), // and literal elsewhere
+        ("$a", "$a", true),
+        (r"\(a$\)", "ab", false), // GNU: an anchor at the end of a subexpression
+        ("^$", "", true),
+        ("$", "x", true),
+    ];
+
+    const EXTENDED_CONTEXT: &[(&str, &str, bool)] = &[
+        ("a^b", "a^b", false), // ^ and $ are anchors everywhere
+        ("a$b", "a$b", false),
+        ("(^a)", "ba", false),
+        ("a)", "a)", true), // ) is literal unless it closes a group
+        ("(a))", "a)", true),
+        ("a{", "a{", true), // { is literal if it cannot start a bound
+    ];
+
+    fn check_context(syntax: Syntax, table: &[(&str, &str, bool)]) {
+        for (pattern, text, expected) in table {
+            let mut s = new_regex_input(pattern);
+            s.extra.syntax = syntax;
+            let (rest, ast) = assert_ok!(parse('/', s));
+            assert_eq!(rest.fragment(), &"", "incomplete parse of {}", pattern);
+            let regex = assert_ok!(Regex::new(&format!("{}", ast)));
+            assert_eq!(regex.is_match(text), *expected, "{} on {:?}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn basic_context() {
+        check_context(Syntax::Basic, BASIC_CONTEXT)
+    }
+
+    #[test]
+    fn extended_context() {
+        check_context(Syntax::Extended, EXTENDED_CONTEXT)
+    }
+
+    #[test]
+    fn extended_leading_quantifier() {
+        // undefined in POSIX, and an error in GNU
+        for pattern in ["*a", "a|*b", "(+a)"] {
+            let mut s = new_regex_input(pattern);
+            s.extra.syntax = Syntax::Extended;
+            let complete = matches!(parse('/', s), Ok((rest, _)) if rest.fragment().is_empty());
+            assert!(!complete, "{} should not parse", pattern);
+        }
+    }
+
+    #[test]
+    fn class_named() {
+        match_modern_syntax("[[:alpha:]]");
+        match_modern_syntax("[[:digit:]a-f]");
+    }
+
+    #[test]
+    fn class_unknown_name() {
+        assert!(parse_complete('/', "[[:vowel:]]").is_err());
+    }
+
+    fn class_matches(pattern: &str, text: &str) -> bool {
+        class_matches_posix(false, pattern, text)
+    }
+
+    fn class_matches_posix(posix: bool, pattern: &str, text: &str) -> bool {
+        let mut s = new_regex_input(pattern);
+        s.extra.syntax = Syntax::Extended;
+        s.extra.posix = posix;
+        let (rest, ast) = assert_ok!(parse('/', s));
+        assert_eq!(rest.fragment(), &"", "incomplete parse of {}", pattern);
+        assert_ok!(Regex::new(&format!("^{}$", ast))).is_match(text)
+    }
+
+    #[test]
+    fn class_bracket_first() {
+        assert!(class_matches("[]a]", "]"));
+        assert!(class_matches("[^]a]", "b"));
+        assert!(!class_matches("[^]a]", "]"));
+    }
+
+    #[test]
+    fn class_hyphen_at_ends() {
+        assert!(class_matches("[-a]", "-"));
+        assert!(class_matches("[a-]", "-"));
+        assert!(!class_matches("[a-]", "b"));
+        assert!(class_matches("[%--]", ",")); // - as the end of a range
+    }
+
+    #[test]
+    fn class_collating_elements() {
+        assert!(class_matches("[[.-.]]", "-"));
+        assert!(class_matches("[[=a=]b]", "a"));
+        assert!(class_matches("[[.a.]-c]", "b"));
+    }
+
+    #[test]
+    fn class_special_literals() {
+        assert!(class_matches("[[]", "["));
+        assert!(class_matches("[a^&~]", "^"));
+    }
+
+    #[test]
+    fn class_gnu_escapes() {
+        assert!(class_matches(r"[\n]", "\n"));
+        assert!(!class_matches(r"[\n]", "\\"));
+        assert!(class_matches(r"[^\t]", "t"));
+        assert!(class_matches(r"[\\]", "\\"));
+        assert!(class_matches(r"[\t-~]", " "));
+        // POSIX: \ is literal
+        assert!(class_matches_posix(true, r"[\n]", "\\"));
+        assert!(class_matches_posix(true, r"[\n]", "n"));
+        assert!(!class_matches_posix(true, r"[\n]", "\n"));
+    }
 }
@@ -0,0 +1,263 @@
+use crate::regex::Groups;
+use crate::regex::parser::{Input, Progress, Syntax};
+
+use nom::character::complete::anychar;
+use nom::error::{Error, ErrorKind};
+
+// GNU extensions to change the case of the replacement
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaseConversion {
+    Upper, // \U until \E
+    Lower, // \L until \E
+    UpperNext, // \u next character only
+    LowerNext, // \l next character only
+    End, // \E
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Piece {
+    Literal(String),
+    Group(usize), // 0 is the whole match, written & in sed syntax
+    NamedGroup(String),
+    Case(CaseConversion),
+}
+
+// the replacement part of s/regex/replacement/, parsed once and applied to each match
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Replacement {
+    pub pieces: Vec<Piece>,
+}
+
+impl Replacement {
+    fn push_char(&mut self, c: char) {
+        match self.pieces.last_mut() {
+            Some(Piece::Literal(s)) => s.push(c),
+            _ => self.pieces.push(Piece::Literal(c.to_string())),
+        }
+    }
+
+    // the text of the replacement, if it has no group references or case conversions
+    pub fn literal(&self) -> Option<&str> {
+        match self.pieces.as_slice() {
+            [] => Some(""),
+            [Piece::Literal(s)] => Some(s),
+            _ => None,
+        }
+    }
+
+    // the highest group number referred to, or 0
+    pub fn max_group(&self) -> usize {
+        self.pieces.iter().filter_map(|p| match p {
+            Piece::Group(i) => Some(*i),
+            _ => None,
+        }).max().unwrap_or(0)
+    }
+}
+
+// case conversion state while expanding a replacement
+struct Case {
+    mode: Option<CaseConversion>, // Upper or Lower
+    next: Option<CaseConversion>, // UpperNext or LowerNext
+}
+
+impl Case {
+    fn push_str(&mut self, s: &str, dst: &mut String) {
+        let mut chars = s.chars();
+        if let Some(next) = self.next {
+            match chars.next() {
+                Some(c) if next == CaseConversion::UpperNext => dst.extend(c.to_uppercase()),
+                Some(c) => dst.extend(c.to_lowercase()),
+                None => return,
+            }
+            self.next = None;
+        }
+        match self.mode {
+            Some(CaseConversion::Upper) => chars.for_each(|c| dst.extend(c.to_uppercase())),
+            Some(CaseConversion::Lower) => chars.for_each(|c| dst.extend(c.to_lowercase())),
+            _ => dst.push_str(chars.as_str()),
+        }
+    }
+}
+
+impl Replacement {
+    // append the replacement for one match
+    pub fn append(&self, groups: &impl Groups, dst: &mut String) {
+        let mut case = Case { mode: None, next: None };
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => case.push_str(s, dst),
+                Piece::Group(i) => case.push_str(groups.group(*i).unwrap_or(""), dst),
+                Piece::NamedGroup(name) => case.push_str(groups.name(name).unwrap_or(""), dst),
+                Piece::Case(c) => match c {
+                    CaseConversion::Upper | CaseConversion::Lower => case.mode = Some(*c),
+                    CaseConversion::UpperNext | CaseConversion::LowerNext => case.next = Some(*c),
+                    CaseConversion::End => {
+                        case.mode = None;
+                        case.next = None;
+                    },
+                },
+            }
+        }
+    }
+}
+
+// a group name or number following $ in Teal syntax, as in the regex crate
+fn dollar_reference(s: Input) -> Progress<Piece> {
+    let braced = s.fragment().starts_with('{');
+    let text = if braced { &s.fragment()[1..] } else { s.fragment() };
+    let len = if braced {
+        text.find('}').ok_or_else(|| nom::Err::Error(Error::new(s, ErrorKind::Char)))?
+    } else {
+        text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len())
+    };
+    if len == 0 {
+        return Err(nom::Err::Error(Error::new(s, ErrorKind::Fail)))
+    }
+    let name = &text[..len];
+    let piece = match name.parse::<usize>() {
+        Ok(i) => Piece::Group(i),
+        Err(_) => Piece::NamedGroup(name.to_string()),
+    };
+    let consumed = if braced { len + 2 } else { len };
+    let (s, _) = nom::bytes::complete::take(consumed)(s)?;
+    Ok((s, piece))
+}
+
+// parse up to, but not including, the unescaped end_char
+pub fn parse(end_char: char, mut s: Input) -> Progress<Replacement> {
+    let mut replacement = Replacement::default();
+    let syntax = s.extra.syntax;
+    loop {
+        let (rest, c) = match anychar::<Input, Error<Input>>(s) {
+            Ok(progress) => progress,
+            // missing end_char is reported by the caller
+            Err(_) => return Ok((s, replacement)),
+        };
+        if c == end_char {
+            return Ok((s, replacement))
+        }
+        s = rest;
+        match c {
+            '&' if syntax != Syntax::Teal => replacement.pieces.push(Piece::Group(0)),
+            '$' if syntax == Syntax::Teal => {
+                if let Ok((rest, _)) = nom::character::complete::char::<Input, Error<Input>>('$')(s) {
+                    replacement.push_char('$');
+                    s = rest;
+                } else {
+                    let (rest, piece) = dollar_reference(s)?;
+                    replacement.pieces.push(piece);
+                    s = rest;
+                }
+            },
+            '\\' => {
+                let (rest, e) = anychar(s)?;
+                s = rest;
+                use CaseConversion::*;
+                match e {
+                    '1'..='9' => replacement.pieces.push(Piece::Group(e.to_digit(10).unwrap() as usize)),
+                    'n' => replacement.push_char('\n'),
+                    't' => replacement.push_char('\t'),
+                    'r' => replacement.push_char('\r'),
+                    'U' => replacement.pieces.push(Piece::Case(Upper)),
+                    'L' => replacement.pieces.push(Piece::Case(Lower)),
+                    'u' => replacement.pieces.push(Piece::Case(UpperNext)),
+                    'l' => replacement.pieces.push(Piece::Case(LowerNext)),
+                    'E' => replacement.pieces.push(Piece::Case(End)),
+                    // \& \\ \newline and the escaped end_char
+                    _ => replacement.push_char(e),
+                }
+            },
+            _ => replacement.push_char(c),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::Piece::*;
+    use crate::regex::{Matcher, replacen};
+    use crate::regex::parser::new_regex_input;
+    use ::regex::Regex;
+    use assert_ok::assert_ok;
+
+    fn parsed(syntax: Syntax, text: &str) -> Replacement {
+        let mut s = new_regex_input(text);
+        s.extra.syntax = syntax;
+        let (rest, replacement) = assert_ok!(parse('/', s));
+        assert_eq!(rest.fragment(), &"");
+        replacement
+    }
+
+    fn replaced(syntax: Syntax, pattern: &str, replacement: &str, source: &str) -> String {
+        let matcher = Matcher::Fast(Regex::new(pattern).unwrap());
+        let mut destination = String::new();
+        if replacen(&matcher, source, &mut destination, 0, 1, &parsed(syntax, replacement)) {
+            destination
+        } else {
+            source.to_string()
+        }
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(parsed(Syntax::Extended, "foo").pieces, [Literal("foo".to_string())])
+    }
+
+    #[test]
+    fn group_reference() {
+        assert_eq!(parsed(Syntax::Extended, r"foo\1").pieces, [Literal("foo".to_string()), Group(1)])
+    }
+
+    #[test]
+    fn max_group() {
+        assert_eq!(parsed(Syntax::Basic, r"&\2\1").max_group(), 2);
+        assert_eq!(parsed(Syntax::Basic, "&").max_group(), 0);
+    }
+
+    #[test]
+    fn dollar_is_literal() {
+        assert_eq!(parsed(Syntax::Extended, "$foo").pieces, [Literal("$foo".to_string())])
+    }
+
+    #[test]
+    fn escaped_delimiter() {
+        assert_eq!(parsed(Syntax::Basic, r"a\/b").pieces, [Literal("a/b".to_string())])
+    }
+
+    #[test]
+    fn stops_at_delimiter() {
+        let (rest, _) = assert_ok!(parse('/', new_regex_input("a/g")));
+        assert_eq!(rest.fragment(), &"/g");
+    }
+
+    #[test]
+    fn ampersand() {
+        assert_eq!(replaced(Syntax::Basic, "b+", r"[&]\&", "abbc"), "a[bb]&c")
+    }
+
+    #[test]
+    fn newline() {
+        assert_eq!(replaced(Syntax::Basic, ",", r"\n", "a,b"), "a\nb")
+    }
+
+    #[test]
+    fn upper_until_end() {
+        assert_eq!(replaced(Syntax::Basic, "(a+)(b+)", r"\U\1\E\2", "aabb"), "AAbb")
+    }
+
+    #[test]
+    fn upper_next() {
+        assert_eq!(replaced(Syntax::Basic, r"(\w+) (\w+)", r"\u\1 \u\2", "hello world"), "Hello World")
+    }
+
+    #[test]
+    fn lower_with_upper_next() {
+        assert_eq!(replaced(Syntax::Basic, ".*", r"\L\u&", "hELLO"), "Hello")
+    }
+
+    #[test]
+    fn teal_named_group() {
+        assert_eq!(replaced(Syntax::Teal, "(?P<x>a)", "${x}$1$$&", "a"), "aa$&")
+    }
+}